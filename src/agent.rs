@@ -0,0 +1,439 @@
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::path::{Path, PathBuf};
+
+use crate::client::BwsClient;
+use crate::error::BwsError;
+use crate::format::Format;
+use crate::render::{ResolveOptions, Template, render_template};
+
+/// Environment variable pointing other invocations at a running agent
+pub const AGENT_SOCK_ENV: &str = "BWS_AGENT_SOCK";
+
+/// One request from a client to the agent, sent as a single JSON line
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AgentRequest {
+    Get {
+        id: String,
+    },
+    GetByKey {
+        key: String,
+        organization_id: String,
+    },
+    List {
+        organization_id: String,
+    },
+    GetByIds {
+        ids: Vec<String>,
+    },
+    Render {
+        source: String,
+        text: String,
+        #[serde(default)]
+        format: Format,
+        organization_id: String,
+        #[serde(default)]
+        options: ResolveOptions,
+    },
+}
+
+/// The agent's reply to one request, sent as a single JSON line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AgentResponse {
+    Ok(JsonValue),
+    Error {
+        kind: String,
+        message: String,
+        status: Option<u16>,
+        exit_code: u8,
+        /// The missing key of `key_not_found`, or the response body of an sdk-server error, so
+        /// the client can rebuild the typed error
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+}
+
+impl From<BwsError> for AgentResponse {
+    fn from(err: BwsError) -> Self {
+        let detail = match &err {
+            BwsError::KeyNotFound { key } => Some(key.clone()),
+            BwsError::Unauthorized { body, .. }
+            | BwsError::NotFound { body, .. }
+            | BwsError::BadRequest { body, .. }
+            | BwsError::ServerError { body, .. } => Some(body.clone()),
+            _ => None,
+        };
+        Self::Error {
+            kind: err.kind().to_string(),
+            message: err.to_string(),
+            status: err.status(),
+            exit_code: err.exit_code(),
+            detail,
+        }
+    }
+}
+
+/// The error an agent reply stands for: the typed [`BwsError`] when the reply carries enough to
+/// rebuild it, so callers handle a missing secret the same with and without the agent, and
+/// [`BwsError::Agent`] otherwise
+fn reply_error(
+    kind: String,
+    message: String,
+    status: Option<u16>,
+    exit_code: u8,
+    detail: Option<String>,
+) -> BwsError {
+    let typed = match (kind.as_str(), status, detail) {
+        ("key_not_found", _, Some(key)) => Some(BwsError::KeyNotFound { key }),
+        ("not_found", Some(status), Some(body)) => Some(BwsError::NotFound { status, body }),
+        ("unauthorized", Some(status), Some(body)) => Some(BwsError::Unauthorized { status, body }),
+        ("bad_request", Some(status), Some(body)) => Some(BwsError::BadRequest { status, body }),
+        ("server_error", Some(status), Some(body)) => Some(BwsError::ServerError { status, body }),
+        _ => None,
+    };
+    typed.unwrap_or(BwsError::Agent {
+        kind,
+        message,
+        status,
+        exit_code,
+    })
+}
+
+/// `$XDG_RUNTIME_DIR/bws-connector/agent.sock`, falling back to `/tmp/bws-connector-$UID/agent.sock`
+pub fn default_socket_path() -> PathBuf {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR").filter(|v| !v.is_empty()) {
+        Some(runtime) => PathBuf::from(runtime).join("bws-connector"),
+        // SAFETY: getuid has no preconditions and cannot fail.
+        #[cfg(unix)]
+        None => std::env::temp_dir().join(format!("bws-connector-{}", unsafe { libc::getuid() })),
+        #[cfg(not(unix))]
+        None => std::env::temp_dir().join("bws-connector"),
+    };
+    dir.join("agent.sock")
+}
+
+/// Send one request to the agent listening on `socket` and decode its reply
+#[cfg(unix)]
+pub async fn call<T: DeserializeOwned>(socket: &Path, req: &AgentRequest) -> Result<T, BwsError> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let unavailable = |source| BwsError::AgentUnavailable {
+        path: socket.display().to_string(),
+        source,
+    };
+    check_socket_owner(socket).map_err(unavailable)?;
+    let mut stream = UnixStream::connect(socket).await.map_err(unavailable)?;
+    let mut line = serde_json::to_vec(req).expect("agent requests always serialize");
+    line.push(b'\n');
+    stream.write_all(&line).await.map_err(unavailable)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .await
+        .map_err(unavailable)?;
+    let invalid = |source| BwsError::InvalidResponse {
+        what: "agent".to_string(),
+        source,
+    };
+    match serde_json::from_str(&reply).map_err(invalid)? {
+        AgentResponse::Ok(value) => serde_json::from_value(value).map_err(invalid),
+        AgentResponse::Error {
+            kind,
+            message,
+            status,
+            exit_code,
+            detail,
+        } => Err(reply_error(kind, message, status, exit_code, detail)),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn call<T: DeserializeOwned>(socket: &Path, _req: &AgentRequest) -> Result<T, BwsError> {
+    Err(BwsError::AgentUnavailable {
+        path: socket.display().to_string(),
+        source: std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the agent needs Unix domain sockets",
+        ),
+    })
+}
+
+/// Serve requests on `socket` with `client` until interrupted.
+///
+/// The socket is created mode 0600 in a 0700 directory so only the current user can reach the
+/// agent. An existing directory must already be a real directory owned by us with no access for
+/// others, or the agent refuses to start. A stale socket left by a crashed agent is replaced; a
+/// live one is an error.
+#[cfg(unix)]
+pub async fn serve(client: BwsClient, socket: &Path) -> anyhow::Result<()> {
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::signal::unix::{SignalKind, signal};
+
+    if let Some(dir) = socket.parent().filter(|d| !d.as_os_str().is_empty()) {
+        private_socket_dir(dir)?;
+    }
+    if socket.exists() {
+        anyhow::ensure!(
+            UnixStream::connect(socket).await.is_err(),
+            "an agent is already listening on {}",
+            socket.display()
+        );
+        std::fs::remove_file(socket)
+            .with_context(|| format!("removing stale socket {}", socket.display()))?;
+    }
+
+    // SAFETY: umask only swaps the process file mode mask; the previous mask is restored below.
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    unsafe { libc::umask(previous) };
+    let listener = listener.with_context(|| format!("binding {}", socket.display()))?;
+
+    println!(
+        "{}={}; export {};",
+        AGENT_SOCK_ENV,
+        socket.display(),
+        AGENT_SOCK_ENV
+    );
+
+    let mut interrupt = signal(SignalKind::interrupt()).context("installing signal handler")?;
+    let mut terminate = signal(SignalKind::terminate()).context("installing signal handler")?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted.context("accepting agent connection")?;
+                let client = client.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(&client, stream).await {
+                        eprintln!("warning: agent connection: {:#}", e);
+                    }
+                });
+            }
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+
+    std::fs::remove_file(socket).with_context(|| format!("removing {}", socket.display()))
+}
+
+/// Create the socket dir mode 0700, or check that an existing one is not a symlink, is owned by
+/// the current user and has mode 0700, so no other user can swap the socket
+#[cfg(unix)]
+fn private_socket_dir(dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    if let Some(parent) = dir.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating socket dir {}", dir.display()))?;
+    }
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("creating socket dir {}", dir.display()));
+        }
+    }
+    let metadata = std::fs::symlink_metadata(dir)
+        .with_context(|| format!("reading socket dir {}", dir.display()))?;
+    // SAFETY: getuid has no preconditions and cannot fail.
+    let uid = unsafe { libc::getuid() };
+    anyhow::ensure!(
+        metadata.file_type().is_dir(),
+        "socket dir {} is not a directory (or is a symlink)",
+        dir.display()
+    );
+    anyhow::ensure!(
+        metadata.uid() == uid,
+        "socket dir {} is owned by uid {}, not the current user",
+        dir.display(),
+        metadata.uid()
+    );
+    anyhow::ensure!(
+        metadata.mode() & 0o777 == 0o700,
+        "socket dir {} has mode {:o}; it must be 0700",
+        dir.display(),
+        metadata.mode() & 0o777
+    );
+    Ok(())
+}
+
+/// Refuse a socket owned by another user, who could be impersonating the agent
+#[cfg(unix)]
+fn check_socket_owner(socket: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let owner = std::fs::metadata(socket)?.uid();
+    // SAFETY: getuid has no preconditions and cannot fail.
+    if owner != unsafe { libc::getuid() } {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("the socket is owned by uid {}, not the current user", owner),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_client: BwsClient, _socket: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("the agent needs Unix domain sockets")
+}
+
+/// Answer every request line on one connection
+#[cfg(unix)]
+async fn handle_connection(
+    client: &BwsClient,
+    stream: tokio::net::UnixStream,
+) -> anyhow::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<AgentRequest>(&line) {
+            Ok(req) => dispatch(client, req).await,
+            Err(e) => AgentResponse::Error {
+                kind: "bad_request".to_string(),
+                message: format!("invalid agent request: {}", e),
+                status: None,
+                exit_code: 6,
+                detail: None,
+            },
+        };
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        writer.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+async fn dispatch(client: &BwsClient, req: AgentRequest) -> AgentResponse {
+    fn reply<T: Serialize>(result: Result<T, BwsError>) -> AgentResponse {
+        match result {
+            Ok(value) => AgentResponse::Ok(serde_json::to_value(value).unwrap_or_default()),
+            Err(err) => err.into(),
+        }
+    }
+
+    match req {
+        AgentRequest::Get { id } => reply(client.get(&id).await),
+        AgentRequest::GetByKey {
+            key,
+            organization_id,
+        } => reply(client.get_by_key(&key, &organization_id).await),
+        AgentRequest::List { organization_id } => reply(client.list(&organization_id).await),
+        AgentRequest::GetByIds { ids } => reply(client.get_by_ids(ids).await),
+        AgentRequest::Render {
+            source,
+            text,
+            format,
+            organization_id,
+            options,
+        } => {
+            let template = Template::parse_as(source, text, format);
+            reply(render_template(&template, client, &organization_id, &options, false).await)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::render::resolve;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_request_wire_format() {
+        let req = AgentRequest::GetByKey {
+            key: "harbor".into(),
+            organization_id: "org".into(),
+        };
+        let line = serde_json::to_string(&req).unwrap();
+        assert_eq!(
+            line,
+            r#"{"op":"get_by_key","key":"harbor","organization_id":"org"}"#
+        );
+        assert_eq!(serde_json::from_str::<AgentRequest>(&line).unwrap(), req);
+    }
+
+    #[test]
+    fn test_error_reply_keeps_exit_code() {
+        let reply = AgentResponse::from(BwsError::KeyNotFound { key: "nope".into() });
+        let line = serde_json::to_string(&reply).unwrap();
+        let AgentResponse::Error {
+            kind, exit_code, ..
+        } = serde_json::from_str(&line).unwrap()
+        else {
+            panic!("expected an error reply");
+        };
+        assert_eq!(kind, "key_not_found");
+        assert_eq!(exit_code, 4);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_typed_errors_through_agent() {
+        let port = mock::with_secrets(&[("id-a", "a", "1")]).await;
+        let dir = std::env::temp_dir().join(format!("bws-agent-test-{}", std::process::id()));
+        let socket = dir.join("agent.sock");
+        let path = socket.clone();
+        tokio::spawn(async move { serve(mock::client(port), &path).await });
+        for _ in 0..200 {
+            if socket.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let client = BwsClient::from_agent(&socket);
+
+        let missing_id = client.get("id-nope").await;
+        assert!(matches!(
+            missing_id,
+            Err(BwsError::NotFound { status: 404, .. })
+        ));
+        let missing_key = client.get_by_key("nope", "org").await;
+        assert!(matches!(missing_key, Err(BwsError::KeyNotFound { key }) if key == "nope"));
+
+        // one unknown id in a batch falls back to fetching one by one
+        let ids = BTreeSet::from(["id-a".to_string(), "id-nope".to_string()]);
+        let resolution = resolve(&client, "org", &BTreeSet::new(), &ids)
+            .await
+            .unwrap();
+        assert_eq!(resolution.get_id("id-a").unwrap().unwrap().value, "1");
+        let template = Template::parse("t", "bws://id:id-a bws://id:id-nope?default=d".into());
+        assert_eq!(template.render(&resolution, false).output, "1 d");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_refuses_shared_socket_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("bws-agent-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let socket = dir.join("agent.sock");
+        let err = serve(mock::client(1), &socket).await.unwrap_err();
+        assert!(err.to_string().contains("must be 0700"), "{}", err);
+        assert!(!socket.exists());
+
+        // a symlink to a private directory is refused as well
+        let private = dir.join("private");
+        std::fs::create_dir(&private).unwrap();
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&private, &link).unwrap();
+        let err = serve(mock::client(1), &link.join("agent.sock"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("symlink"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+use anyhow::Context;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::client::BwsClient;
+use crate::error::BwsError;
+use crate::format::Format;
+use crate::render::{
+    NestedSecret, PlaceholderReport, Rendered, ResolveOptions, Template, fail_unresolved,
+    read_input, resolve_with,
+};
+
+/// What `render --input-dir` does with files that contain no placeholders
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NonTemplates {
+    /// Copy them to the output directory unchanged, keeping their permissions
+    #[default]
+    Copy,
+    /// Leave them out of the output directory
+    Skip,
+}
+
+/// A set of templates rendered together, so every secret they reference is fetched once.
+///
+/// Nothing is written until every template has rendered, and each output is written to a
+/// temporary file and renamed into place, so readers never see a partially written file.
+#[derive(Default)]
+pub struct Batch {
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    input: PathBuf,
+    output: PathBuf,
+    content: Content,
+}
+
+enum Content {
+    Template(Template),
+    /// A file without placeholders, copied with its own permissions
+    Copy {
+        data: Vec<u8>,
+        mode: Option<u32>,
+    },
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add every file under `input_dir`, mirrored under `output_dir`. The format of each file is
+    /// `format`, or detected from its extension.
+    pub fn add_dir(
+        &mut self,
+        input_dir: &Path,
+        output_dir: &Path,
+        format: Option<Format>,
+        non_templates: NonTemplates,
+    ) -> anyhow::Result<()> {
+        // an output directory inside the input directory must not be rendered into itself
+        let skip = std::fs::canonicalize(output_dir).ok();
+        let mut files = Vec::new();
+        walk(input_dir, skip.as_deref(), &mut files)?;
+        files.sort();
+        for input in files {
+            let relative = input.strip_prefix(input_dir).unwrap_or(&input);
+            let output = output_dir.join(relative);
+            let data =
+                std::fs::read(&input).with_context(|| format!("reading {}", input.display()))?;
+            let template = String::from_utf8(data).map(|text| {
+                let format = format.unwrap_or_else(|| Format::from_path(&input));
+                Template::parse_as(input.display().to_string(), text, format)
+            });
+            let content = match template {
+                Ok(template) if !template.is_static() => Content::Template(template),
+                _ if non_templates == NonTemplates::Skip => continue,
+                Ok(template) => Content::Copy {
+                    data: template.text.into_bytes(),
+                    mode: file_mode(&input),
+                },
+                Err(e) => Content::Copy {
+                    data: e.into_bytes(),
+                    mode: file_mode(&input),
+                },
+            };
+            self.push(input, output, content)?;
+        }
+        Ok(())
+    }
+
+    /// Add a single template rendered to `output`
+    pub fn add_file(
+        &mut self,
+        input: PathBuf,
+        output: PathBuf,
+        format: Option<Format>,
+    ) -> anyhow::Result<()> {
+        let text = read_input(Some(input.clone()))
+            .with_context(|| format!("reading {}", input.display()))?;
+        let format = format.unwrap_or_else(|| Format::from_path(&input));
+        let template = Template::parse_as(input.display().to_string(), text, format);
+        self.push(input, output, Content::Template(template))
+    }
+
+    fn push(&mut self, input: PathBuf, output: PathBuf, content: Content) -> anyhow::Result<()> {
+        if let Some(other) = self.entries.iter().find(|e| e.output == output) {
+            anyhow::bail!(
+                "{} and {} would both be written to {}",
+                other.input.display(),
+                input.display(),
+                output.display()
+            );
+        }
+        self.entries.push(Entry {
+            input,
+            output,
+            content,
+        });
+        Ok(())
+    }
+
+    /// The files that contain placeholders
+    pub fn templates(&self) -> impl Iterator<Item = &Template> {
+        self.entries.iter().filter_map(|e| match &e.content {
+            Content::Template(template) => Some(template),
+            Content::Copy { .. } => None,
+        })
+    }
+
+    /// Resolve the secrets of every template at once and render them
+    pub async fn render(
+        &self,
+        client: &BwsClient,
+        org_id: &str,
+        options: &ResolveOptions,
+        verbose: bool,
+    ) -> Result<RenderedBatch<'_>, BwsError> {
+        let keys: BTreeSet<String> = self.templates().flat_map(Template::keys).collect();
+        let ids: BTreeSet<String> = self.templates().flat_map(Template::ids).collect();
+        let resolution = resolve_with(client, org_id, &keys, &ids, options, verbose).await?;
+        let files = self
+            .entries
+            .iter()
+            .map(|entry| RenderedFile {
+                entry,
+                rendered: match &entry.content {
+                    Content::Template(template) => Some(template.render(&resolution, verbose)),
+                    Content::Copy { .. } => None,
+                },
+            })
+            .collect();
+        Ok(RenderedBatch { files })
+    }
+}
+
+/// Output of rendering a [`Batch`], not yet written
+pub struct RenderedBatch<'a> {
+    files: Vec<RenderedFile<'a>>,
+}
+
+struct RenderedFile<'a> {
+    entry: &'a Entry,
+    rendered: Option<Rendered>,
+}
+
+impl RenderedBatch<'_> {
+    /// Every placeholder, across every file
+    pub fn placeholders(&self) -> impl Iterator<Item = &PlaceholderReport> {
+        self.files
+            .iter()
+            .filter_map(|f| f.rendered.as_ref())
+            .flat_map(|r| r.placeholders.iter())
+    }
+
+    /// Secrets fetched by `--recursive` for references inside secret values, across every file
+    pub fn nested(&self) -> impl Iterator<Item = &NestedSecret> {
+        self.files
+            .iter()
+            .filter_map(|f| f.rendered.as_ref())
+            .flat_map(|r| r.nested.iter())
+    }
+
+    /// Placeholders left as-is, across every file
+    pub fn unresolved(&self) -> impl Iterator<Item = &PlaceholderReport> {
+        self.placeholders().filter(|p| !p.resolved)
+    }
+
+    /// Fail with every unresolved reference, for `--strict`
+    pub fn ensure_resolved(&self) -> Result<(), BwsError> {
+        fail_unresolved(self.unresolved())
+    }
+
+    /// Fail with the `?required` and malformed references left unresolved
+    pub fn ensure_required(&self) -> Result<(), BwsError> {
+        fail_unresolved(self.unresolved().filter(|p| p.required))
+    }
+
+    /// JSON report for `--report`, keyed by input file
+    pub fn report(&self) -> JsonValue {
+        let files: BTreeMap<String, JsonValue> = self
+            .files
+            .iter()
+            .filter_map(|f| {
+                let rendered = f.rendered.as_ref()?;
+                Some((f.entry.input.display().to_string(), rendered.report()))
+            })
+            .collect();
+        serde_json::json!({ "files": files })
+    }
+
+    /// Write every output atomically; rendered files get `mode`
+    pub fn write(&self, mode: u32) -> anyhow::Result<()> {
+        for file in &self.files {
+            let (data, mode) = match (&file.rendered, &file.entry.content) {
+                (Some(rendered), _) => (rendered.output.as_bytes(), Some(mode)),
+                (None, Content::Copy { data, mode }) => (data.as_slice(), *mode),
+                (None, Content::Template(_)) => unreachable!("templates are always rendered"),
+            };
+            write_atomic(&file.entry.output, data, mode)
+                .with_context(|| format!("writing {}", file.entry.output.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Number of files rendered and copied
+    pub fn counts(&self) -> (usize, usize) {
+        let rendered = self.files.iter().filter(|f| f.rendered.is_some()).count();
+        (rendered, self.files.len() - rendered)
+    }
+}
+
+/// Collect the regular files under `dir`, not descending into `skip` or symlinked directories
+fn walk(dir: &Path, skip: Option<&Path>, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("reading {}", dir.display()))?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if skip.is_none() || std::fs::canonicalize(&path).ok().as_deref() != skip {
+                walk(&path, skip, files)?;
+            }
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path)
+        .ok()
+        .map(|m| m.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// Write `data` to a temporary file next to `path`, then rename it into place
+fn write_atomic(path: &Path, data: &[u8], mode: Option<u32>) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let dir = path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let name = path.file_name().context("output path has no file name")?;
+    let tmp = dir.join(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result = (|| {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            // created private, then set to the exact mode below regardless of the umask
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp)?;
+        file.write_all(data)?;
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_dir_mirrors_tree() {
+        let root = std::env::temp_dir().join(format!("bws-batch-test-{}", std::process::id()));
+        let input = root.join("charts");
+        std::fs::create_dir_all(input.join("app/templates")).unwrap();
+        std::fs::write(
+            input.join("app/values.yaml"),
+            "pass: bws://harbor/pg_pass\n",
+        )
+        .unwrap();
+        std::fs::write(input.join("app/templates/cm.yaml"), "kind: ConfigMap\n").unwrap();
+        std::fs::write(input.join("app/logo.png"), [0x89, b'P', b'N', b'G', 0xff]).unwrap();
+        // output inside the input tree is not walked
+        let output = input.join("out");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("stale.yaml"), "bws://x\n").unwrap();
+
+        let mut batch = Batch::new();
+        batch
+            .add_dir(&input, &output, None, NonTemplates::Copy)
+            .unwrap();
+        let outputs: Vec<&Path> = batch.entries.iter().map(|e| e.output.as_path()).collect();
+        assert_eq!(
+            outputs,
+            [
+                output.join("app/logo.png"),
+                output.join("app/templates/cm.yaml"),
+                output.join("app/values.yaml"),
+            ]
+        );
+        assert_eq!(batch.templates().count(), 1);
+
+        let mut skipping = Batch::new();
+        skipping
+            .add_dir(&input, &output, None, NonTemplates::Skip)
+            .unwrap();
+        assert_eq!(skipping.entries.len(), 1);
+        assert!(
+            skipping
+                .add_file(
+                    input.join("app/values.yaml"),
+                    output.join("app/values.yaml"),
+                    None
+                )
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_sets_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("bws-batch-mode-{}", std::process::id()));
+        let path = dir.join("nested/out.txt");
+        write_atomic(&path, b"one", Some(0o640)).unwrap();
+        write_atomic(&path, b"two", Some(0o600)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "two");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
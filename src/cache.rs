@@ -0,0 +1,329 @@
+use anyhow::Context;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Length of the random nonce stored in front of every cache file
+const NONCE_LEN: usize = 24;
+
+/// When cached entries are used instead of the sdk-server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Use fresh entries, otherwise fetch and store
+    #[default]
+    Normal,
+    /// Like `Normal`, but fall back to stale entries when the sdk-server is unreachable or failing
+    StaleIfError,
+    /// Never contact the sdk-server; use cached entries regardless of age
+    Offline,
+}
+
+/// Settings for the on-disk secret cache
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    /// Cache directory, defaults to `$XDG_CACHE_HOME/bws-connector`
+    pub dir: Option<PathBuf>,
+    /// How long entries are considered fresh
+    pub ttl: Duration,
+    pub mode: CacheMode,
+}
+
+/// A cached entry and whether it is still within the TTL
+pub struct Cached<T> {
+    pub value: T,
+    pub fresh: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    stored_at: u64,
+    value: T,
+}
+
+/// Cache of list results and secret values, on disk or in memory.
+///
+/// On disk, entries are encrypted with XChaCha20-Poly1305 under a key derived from the access
+/// token, and file names are keyed hashes of the entry name, so neither contents nor secret ids
+/// are visible on disk and a different token cannot read them.
+pub struct SecretCache {
+    store: Store,
+    ttl: Duration,
+    mode: CacheMode,
+}
+
+enum Store {
+    Disk {
+        dir: PathBuf,
+        key: [u8; 32],
+    },
+    /// Serialized entries held by a long-running process such as the agent
+    Memory(Mutex<HashMap<String, Vec<u8>>>),
+}
+
+impl SecretCache {
+    /// Open (creating if needed) the cache for an access token
+    pub fn open(settings: &CacheSettings, access_token: &str) -> anyhow::Result<Self> {
+        let dir = match &settings.dir {
+            Some(dir) => dir.clone(),
+            None => default_dir().context("cannot locate cache dir; set XDG_CACHE_HOME or HOME")?,
+        };
+        create_private_dir(&dir)?;
+        let key: [u8; 32] = Sha256::new()
+            .chain_update(b"bws-connector cache v1\0")
+            .chain_update(access_token.as_bytes())
+            .finalize()
+            .into();
+        Ok(Self {
+            store: Store::Disk { dir, key },
+            ttl: settings.ttl,
+            mode: settings.mode,
+        })
+    }
+
+    /// A process-local cache that never touches disk
+    pub fn in_memory(ttl: Duration) -> Self {
+        Self {
+            store: Store::Memory(Mutex::new(HashMap::new())),
+            ttl,
+            mode: CacheMode::Normal,
+        }
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /// Read an entry; `None` if missing, unreadable or written under another token
+    pub fn get<T: DeserializeOwned>(&self, name: &str) -> Option<Cached<T>> {
+        let plain = match &self.store {
+            Store::Disk { dir, key } => {
+                let data = std::fs::read(entry_path(dir, key, name)).ok()?;
+                if data.len() < NONCE_LEN {
+                    return None;
+                }
+                let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+                cipher(key)
+                    .decrypt(XNonce::from_slice(nonce), ciphertext)
+                    .ok()?
+            }
+            Store::Memory(entries) => entries.lock().unwrap().get(name)?.clone(),
+        };
+        let entry: Entry<T> = serde_json::from_slice(&plain).ok()?;
+        let age = now().saturating_sub(entry.stored_at);
+        Some(Cached {
+            value: entry.value,
+            fresh: age < self.ttl.as_secs(),
+        })
+    }
+
+    /// Write an entry (atomically, on disk)
+    pub fn put<T: Serialize>(&self, name: &str, value: &T) -> anyhow::Result<()> {
+        let plain = serde_json::to_vec(&Entry {
+            stored_at: now(),
+            value,
+        })?;
+        let (dir, key) = match &self.store {
+            Store::Disk { dir, key } => (dir, key),
+            Store::Memory(entries) => {
+                entries.lock().unwrap().insert(name.to_string(), plain);
+                return Ok(());
+            }
+        };
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher(key)
+            .encrypt(&nonce, plain.as_slice())
+            .map_err(|_| anyhow::anyhow!("failed to encrypt cache entry"))?;
+
+        let path = entry_path(dir, key, name);
+        // unique per writer, so concurrent writes of one entry never share a temporary file
+        let tmp = path.with_extension(format!(
+            "{}.{:016x}.tmp",
+            std::process::id(),
+            OsRng.next_u64()
+        ));
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        let written = write_private(&tmp, &data)
+            .and_then(|()| std::fs::rename(&tmp, &path).context("writing cache entry"));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        written
+    }
+
+    /// Drop an entry
+    pub fn remove(&self, name: &str) {
+        match &self.store {
+            Store::Disk { dir, key } => {
+                let _ = std::fs::remove_file(entry_path(dir, key, name));
+            }
+            Store::Memory(entries) => {
+                entries.lock().unwrap().remove(name);
+            }
+        }
+    }
+}
+
+fn cipher(key: &[u8; 32]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(key.into())
+}
+
+fn entry_path(dir: &Path, key: &[u8; 32], name: &str) -> PathBuf {
+    let digest = Sha256::new()
+        .chain_update(key)
+        .chain_update(name.as_bytes())
+        .finalize();
+    let file: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    dir.join(file)
+}
+
+/// `$XDG_CACHE_HOME/bws-connector`, falling back to `~/.cache/bws-connector`
+pub fn default_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))?;
+    Some(base.join("bws-connector"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Create the cache dir readable only by us. An existing directory is never changed; it is
+/// refused if other users can access it.
+fn create_private_dir(dir: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating cache dir {}", dir.display()))?;
+    }
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    match builder.create(dir) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("creating cache dir {}", dir.display()));
+        }
+    }
+    let metadata =
+        std::fs::metadata(dir).with_context(|| format!("reading cache dir {}", dir.display()))?;
+    anyhow::ensure!(
+        metadata.is_dir(),
+        "cache dir {} is not a directory",
+        dir.display()
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode() & 0o777;
+        anyhow::ensure!(
+            mode & 0o077 == 0,
+            "cache dir {} is accessible to other users (mode {:o}); use a private directory \
+             or restrict it with chmod 700",
+            dir.display(),
+            mode
+        );
+    }
+    Ok(())
+}
+
+fn write_private(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    use std::io::Write;
+    let mut file = options.open(path).context("writing cache entry")?;
+    file.write_all(data).context("writing cache entry")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(dir: &Path, ttl: u64) -> CacheSettings {
+        CacheSettings {
+            dir: Some(dir.to_path_buf()),
+            ttl: Duration::from_secs(ttl),
+            mode: CacheMode::Normal,
+        }
+    }
+
+    #[test]
+    fn test_cache_roundtrip_is_token_bound() {
+        let dir = std::env::temp_dir().join(format!("bws-cache-test-{}", std::process::id()));
+        let cache = SecretCache::open(&settings(&dir, 60), "token-a").unwrap();
+        cache.put("secret:1", &"hunter2".to_string()).unwrap();
+
+        let hit: Cached<String> = cache.get("secret:1").unwrap();
+        assert_eq!(hit.value, "hunter2");
+        assert!(hit.fresh);
+
+        // nothing on disk reveals the value or the entry name
+        for file in std::fs::read_dir(&dir).unwrap() {
+            let file = file.unwrap();
+            assert!(!file.file_name().to_string_lossy().contains("secret"));
+            let data = std::fs::read(file.path()).unwrap();
+            assert!(!String::from_utf8_lossy(&data).contains("hunter2"));
+        }
+
+        let other = SecretCache::open(&settings(&dir, 60), "token-b").unwrap();
+        assert!(other.get::<String>("secret:1").is_none());
+
+        let expired = SecretCache::open(&settings(&dir, 0), "token-a").unwrap();
+        assert!(!expired.get::<String>("secret:1").unwrap().fresh);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cache_dir_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = std::env::temp_dir().join(format!("bws-cache-perm-{}", std::process::id()));
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // a new directory is created private, and writes leave no temporary files behind
+        let dir = base.join("new");
+        let cache = SecretCache::open(&settings(&dir, 60), "token").unwrap();
+        assert_eq!(mode(&dir), 0o700);
+        cache.put("list:org", &"a".to_string()).unwrap();
+        cache.put("list:org", &"b".to_string()).unwrap();
+        assert_eq!(cache.get::<String>("list:org").unwrap().value, "b");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // an existing shared directory is refused and left as it was
+        std::fs::set_permissions(&base, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let err = SecretCache::open(&settings(&base, 60), "token")
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("accessible to other users"),
+            "{}",
+            err
+        );
+        assert_eq!(mode(&base), 0o755);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
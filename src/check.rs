@@ -0,0 +1,235 @@
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::client::BwsClient;
+use crate::error::BwsError;
+use crate::models::SecretIdentifierResponse;
+use crate::overlay::Overlay;
+use crate::render::{Modifier, Template};
+
+/// How `render --check` prints its findings
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CheckFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// No secret has the referenced key (or id)
+    MissingKey,
+    /// More than one secret has the referenced key
+    DuplicateKey,
+    /// The placeholder cannot be parsed: unknown modifier or filter, or unrecognised syntax
+    Malformed,
+}
+
+/// One finding of `render --check`
+#[derive(Clone, Debug, Serialize)]
+pub struct Problem {
+    pub source: String,
+    pub line: usize,
+    pub column: usize,
+    /// The placeholder text, or the start of an unrecognised reference
+    pub placeholder: String,
+    pub kind: ProblemKind,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.source, self.line, self.column, self.placeholder, self.message
+        )
+    }
+}
+
+/// Result of checking a set of templates
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckReport {
+    pub files: usize,
+    pub placeholders: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// Fail when anything was found, after the report has been printed
+    pub fn ensure_clean(&self) -> Result<(), BwsError> {
+        match self.problems.len() {
+            0 => Ok(()),
+            problems => Err(BwsError::CheckFailed { problems }),
+        }
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        write!(
+            f,
+            "checked {} placeholder(s) in {} file(s): {} problem(s)",
+            self.placeholders,
+            self.files,
+            self.problems.len()
+        )
+    }
+}
+
+/// Check every reference in `templates` against the org's secret list, without fetching any
+/// values: keys must exist exactly once, ids must exist, and placeholders must be well formed.
+///
+/// Makes at most one list call. A missing key is not a problem when the placeholder has
+/// `?default` or `?optional`; paths inside values are not checked since that needs the value.
+pub async fn check<'a>(
+    templates: impl IntoIterator<Item = &'a Template>,
+    client: &BwsClient,
+    org_id: &str,
+    overlay: Option<&Overlay>,
+) -> Result<CheckReport, BwsError> {
+    let templates: Vec<&Template> = templates.into_iter().collect();
+    let list = match templates.iter().any(|t| !t.placeholders.is_empty()) {
+        true => client.list(org_id).await?.data,
+        false => Vec::new(),
+    };
+    Ok(check_against(&templates, &list, overlay))
+}
+
+fn check_against(
+    templates: &[&Template],
+    list: &[SecretIdentifierResponse],
+    overlay: Option<&Overlay>,
+) -> CheckReport {
+    let mut ids_by_key: HashMap<&str, Vec<&str>> = HashMap::new();
+    for item in list {
+        ids_by_key.entry(&item.key).or_default().push(&item.id);
+    }
+    let ids: BTreeSet<&str> = list.iter().map(|i| i.id.as_str()).collect();
+
+    let mut report = CheckReport {
+        files: templates.len(),
+        placeholders: 0,
+        problems: Vec::new(),
+    };
+    for template in templates {
+        let mut problems = Vec::new();
+        for ph in &template.placeholders {
+            report.placeholders += 1;
+            let problem = |kind, message| Problem {
+                source: template.source.clone(),
+                line: ph.line,
+                column: ph.column,
+                placeholder: template.text[ph.start..ph.end].to_string(),
+                kind,
+                message,
+            };
+            let fallback = matches!(ph.modifier, Modifier::Default(_) | Modifier::Optional);
+            if let Some(invalid) = &ph.invalid {
+                problems.push(problem(ProblemKind::Malformed, invalid.clone()));
+            } else if ph.by_id {
+                if !ids.contains(ph.key.as_str()) && !fallback {
+                    let message = format!("no secret with id '{}'", ph.key);
+                    problems.push(problem(ProblemKind::MissingKey, message));
+                }
+            } else {
+                // with --env, the environment key is checked when it exists
+                let key = overlay
+                    .and_then(|o| o.key_for(&ph.key))
+                    .filter(|k| ids_by_key.contains_key(k.as_str()))
+                    .unwrap_or_else(|| ph.key.clone());
+                match ids_by_key.get(key.as_str()) {
+                    None if fallback => {}
+                    None => {
+                        let message = format!("secret with key '{}' not found", ph.key);
+                        problems.push(problem(ProblemKind::MissingKey, message));
+                    }
+                    Some(ids) if ids.len() > 1 => {
+                        let message = format!(
+                            "key '{}' matches {} secrets: {}",
+                            key,
+                            ids.len(),
+                            ids.join(", ")
+                        );
+                        problems.push(problem(ProblemKind::DuplicateKey, message));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        for start in template.stray_references() {
+            let (line, column) = position(&template.text, start);
+            let placeholder: String = template.text[start..]
+                .chars()
+                .take_while(|c| !c.is_whitespace())
+                .take(40)
+                .collect();
+            problems.push(Problem {
+                source: template.source.clone(),
+                line,
+                column,
+                placeholder,
+                kind: ProblemKind::Malformed,
+                message: "not a valid placeholder (escape it as bws::// if it is literal text)"
+                    .to_string(),
+            });
+        }
+        problems.sort_by_key(|p| (p.line, p.column));
+        report.problems.extend(problems);
+    }
+    report
+}
+
+/// 1-based line and column (in characters) of a byte offset
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Format;
+
+    #[test]
+    fn test_check_against_list() {
+        let list: Vec<SecretIdentifierResponse> =
+            [("id-1", "harbor"), ("id-2", "dup"), ("id-3", "dup")]
+                .iter()
+                .map(|(id, key)| SecretIdentifierResponse {
+                    id: id.to_string(),
+                    organization_id: "org".into(),
+                    key: key.to_string(),
+                })
+                .collect();
+        let text = "a: bws://harbor/pg_user\n\
+                    b: bws://dup ${bws:id:id-9}\n\
+                    c: bws://nope?optional bws://harbor|upper\n\
+                    d: ${bws:\"unterminated bws:://literal\n";
+        let template = Template::parse_as("t.yaml", text.to_string(), Format::Yaml);
+        let report = check_against(&[&template], &list, None);
+        assert_eq!(report.placeholders, 5);
+        let found: Vec<(usize, ProblemKind)> =
+            report.problems.iter().map(|p| (p.line, p.kind)).collect();
+        assert_eq!(
+            found,
+            [
+                (2, ProblemKind::DuplicateKey),
+                (2, ProblemKind::MissingKey),
+                (3, ProblemKind::Malformed),
+                (4, ProblemKind::Malformed),
+            ]
+        );
+        assert_eq!(report.problems[3].column, 4);
+        assert!(report.ensure_clean().is_err());
+    }
+}
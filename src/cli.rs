@@ -1,23 +1,62 @@
-use clap::{Parser, Subcommand};
+use bws_connector::batch::NonTemplates;
+use bws_connector::check::CheckFormat;
+use bws_connector::expand::DEFAULT_MAX_DEPTH;
+use bws_connector::format::Format;
+use bws_connector::overlay::{EnvRule, Overlay};
+use bws_connector::token::TokenSource;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
+/// Used when neither a flag, an environment variable nor the profile sets them
+pub const DEFAULT_BASE_URL: &str = "http://127.0.0.1:9998/rest/api/1";
+pub const DEFAULT_API_URL: &str = "https://api.bitwarden.com";
+pub const DEFAULT_IDENTITY_URL: &str = "https://identity.bitwarden.com";
+
 #[derive(Parser)]
 #[command(author, version, about = "Bitwarden Connect CLI", long_about = None)]
 pub struct Cli {
-    /// Base URL for the external-secrets bitwarden-sdk-server (e.g. http://localhost:9998/rest/api/1)
-    #[arg(long, default_value = "http://127.0.0.1:9998/rest/api/1")]
-    pub base_url: String,
+    /// Profile from the config file to take connection settings from (or set BWS_PROFILE)
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Config file with profiles (defaults to $XDG_CONFIG_HOME/bws-connector/config.toml, or set BWS_CONFIG)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Base URL for the external-secrets bitwarden-sdk-server [default: http://127.0.0.1:9998/rest/api/1]
+    #[arg(long)]
+    pub base_url: Option<String>,
+
+    /// Organization id (or set WARDEN_ORGANIZATION_ID)
+    #[arg(long)]
+    pub organization_id: Option<String>,
 
     /// Warden access token header value (or set WARDEN_ACCESS_TOKEN)
     #[arg(long)]
     pub access_token: Option<String>,
 
-    /// Optional API URL header
-    #[arg(long, default_value = "https://api.bitwarden.com")]
+    /// Read the access token from a file, e.g. a mounted Kubernetes secret; the agent re-reads it when it changes
+    #[arg(long, conflicts_with_all = ["access_token", "access_token_command", "access_token_keyring", "access_token_stdin"])]
+    pub access_token_file: Option<PathBuf>,
+
+    /// Run a shell command and use its output as the access token
+    #[arg(long, conflicts_with_all = ["access_token", "access_token_keyring", "access_token_stdin"])]
+    pub access_token_command: Option<String>,
+
+    /// Read the access token from this entry of the OS keyring (service "bws-connector"; needs the keyring feature)
+    #[arg(long, value_name = "ENTRY", conflicts_with_all = ["access_token", "access_token_stdin"])]
+    pub access_token_keyring: Option<String>,
+
+    /// Read the access token from the first line of stdin
+    #[arg(long, default_value_t = false, conflicts_with = "access_token")]
+    pub access_token_stdin: bool,
+
+    /// Optional API URL header [default: https://api.bitwarden.com]
+    #[arg(long)]
     pub api_url: Option<String>,
 
-    /// Optional Identity URL header
-    #[arg(long, default_value = "https://identity.bitwarden.com")]
+    /// Optional Identity URL header [default: https://identity.bitwarden.com]
+    #[arg(long)]
     pub identity_url: Option<String>,
 
     /// Optional state path header
@@ -28,9 +67,41 @@ pub struct Cli {
     #[arg(long, default_value_t = false)]
     pub insecure: bool,
 
-    /// Path to a custom CA certificate (PEM) to trust for TLS
+    /// Path to a custom CA certificate bundle (PEM) to trust for TLS; repeat for several bundles
+    #[arg(long)]
+    pub ca_cert: Vec<PathBuf>,
+
+    /// Client certificate for mutual TLS: a PEM chain, or a PKCS#12 bundle (.p12/.pfx) with its key
+    #[arg(long)]
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM private key for --client-cert, when it is not in the certificate file
+    #[arg(long, requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
+    /// Password of a PKCS#12 --client-cert (or set BWS_CLIENT_CERT_PASSWORD)
+    #[arg(long, requires = "client_cert")]
+    pub client_cert_password: Option<String>,
+
+    /// Require this SHA-256 of a SubjectPublicKeyInfo (sha256//<base64>) in the server's chain; repeat to allow several
+    #[arg(long, value_name = "PIN")]
+    pub pin_sha256: Vec<String>,
+
+    /// Proxy for every request: http://, https://, socks5:// or socks5h:// (default: HTTPS_PROXY, HTTP_PROXY, ALL_PROXY)
     #[arg(long)]
-    pub ca_cert: Option<PathBuf>,
+    pub proxy: Option<String>,
+
+    /// Comma-separated hosts, domains and CIDR ranges reached without the proxy (default: NO_PROXY)
+    #[arg(long, value_name = "LIST")]
+    pub no_proxy: Option<String>,
+
+    /// Seconds allowed for connecting, including the TLS handshake
+    #[arg(long, value_name = "SECS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds allowed for a whole request
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
 
     /// Try to parse the secret's `value` field as JSON and pretty-print it
     #[arg(long, default_value_t = false)]
@@ -44,16 +115,127 @@ pub struct Cli {
     #[arg(long, default_value_t = false)]
     pub verbose: bool,
 
+    /// Cache list results and secret values on disk, encrypted with a key derived from the access token
+    #[arg(long, default_value_t = false)]
+    pub cache: bool,
+
+    /// Cache directory (defaults to $XDG_CACHE_HOME/bws-connector)
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Seconds a cached entry is used before it is refreshed from the sdk-server
+    #[arg(long, default_value_t = 300)]
+    pub cache_ttl: u64,
+
+    /// Serve everything from the cache, even stale entries, without contacting the sdk-server (implies --cache)
+    #[arg(long, default_value_t = false, conflicts_with = "stale_if_error")]
+    pub offline: bool,
+
+    /// Fall back to stale cached entries when the sdk-server is unreachable or failing (implies --cache)
+    #[arg(long, default_value_t = false)]
+    pub stale_if_error: bool,
+
+    /// Format of errors printed to stderr; json prints {"error": {kind, message, status, exit_code}}
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    pub error_format: ErrorFormat,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Engine {
+    Bws,
+    Jinja,
+}
+
+/// `--recursive` and `--max-depth`, shared by render and get
+#[derive(Args)]
+pub struct RecursiveArgs {
+    /// Also resolve bws:// references found inside fetched secret values
+    #[arg(long, default_value_t = false)]
+    recursive: bool,
+    /// How many levels of references inside values --recursive follows
+    #[arg(long, default_value_t = DEFAULT_MAX_DEPTH, requires = "recursive")]
+    max_depth: usize,
+}
+
+impl Cli {
+    /// The token source given on the command line, if any
+    pub fn token_source(&self) -> Option<TokenSource> {
+        if let Some(token) = &self.access_token {
+            Some(TokenSource::Value(token.clone()))
+        } else if let Some(path) = &self.access_token_file {
+            Some(TokenSource::File(path.clone()))
+        } else if let Some(command) = &self.access_token_command {
+            Some(TokenSource::Command(command.clone()))
+        } else if let Some(entry) = &self.access_token_keyring {
+            Some(TokenSource::Keyring(entry.clone()))
+        } else {
+            self.access_token_stdin.then_some(TokenSource::Stdin)
+        }
+    }
+}
+
+impl RecursiveArgs {
+    /// The depth limit, when recursive resolution was asked for
+    pub fn depth(&self) -> Option<usize> {
+        self.recursive.then_some(self.max_depth)
+    }
+}
+
+/// `render --env` and how environment keys are named
+#[derive(Args)]
+pub struct EnvArgs {
+    /// Resolve each key through its variant for this environment (e.g. prod_harbor for harbor),
+    /// falling back to the key itself
+    #[arg(long = "env", value_name = "ENV")]
+    name: Option<String>,
+    /// Whether the environment is a prefix or suffix of the key
+    #[arg(long, value_enum, default_value_t = EnvRule::Prefix, requires = "name")]
+    env_rule: EnvRule,
+    /// Separator between the environment and the key
+    #[arg(long, default_value = "_", requires = "name")]
+    env_separator: String,
+    /// TOML file with a [ENV] table mapping keys to environment keys, instead of --env-rule
+    #[arg(long, value_name = "FILE", requires = "name")]
+    env_map: Option<PathBuf>,
+}
+
+impl EnvArgs {
+    /// The overlay to resolve through, when --env was given
+    pub fn overlay(&self) -> anyhow::Result<Option<Overlay>> {
+        let Some(env) = &self.name else {
+            return Ok(None);
+        };
+        Ok(Some(match &self.env_map {
+            Some(path) => Overlay::from_map_file(path, env)?,
+            None => Overlay::naming(env, self.env_rule, &self.env_separator),
+        }))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Get a secret by ID
-    Get { id: String },
+    Get {
+        id: String,
+        #[command(flatten)]
+        recursive: RecursiveArgs,
+    },
     /// Get a secret by key (looks up the secret id via list). Optional org overrides env
-    GetByKey { key: String, organization_id: Option<String> },
+    GetByKey {
+        key: String,
+        organization_id: Option<String>,
+        #[command(flatten)]
+        recursive: RecursiveArgs,
+    },
     /// List secrets. Optional org overrides env
     List { organization_id: Option<String> },
     /// Get secrets by IDs (comma separated)
@@ -76,5 +258,110 @@ pub enum Commands {
     /// Delete secrets by ids (comma separated)
     Delete { ids: String },
     /// Render placeholders from stdin or a file, replacing bws://key[/path] entries
-    Render { file: Option<PathBuf> },
+    Render {
+        #[arg(conflicts_with_all = ["input_dir", "files"])]
+        file: Option<PathBuf>,
+        /// Render every file under this directory into --output-dir, keeping the directory structure
+        #[arg(long)]
+        input_dir: Option<PathBuf>,
+        /// Where --input-dir is rendered to
+        #[arg(long, requires = "input_dir")]
+        output_dir: Option<PathBuf>,
+        /// Render IN to OUT; repeat for several files (secrets are resolved once for all of them)
+        #[arg(long = "file", value_name = "IN:OUT", value_parser = parse_file_pair)]
+        files: Vec<(PathBuf, PathBuf)>,
+        /// What to do with files under --input-dir that contain no placeholders
+        #[arg(long, value_enum, default_value_t = NonTemplates::Copy)]
+        non_templates: NonTemplates,
+        /// File mode (octal) of outputs written by --input-dir and --file
+        #[arg(long, value_parser = parse_mode, default_value = "0600")]
+        mode: u32,
+        /// Fail, listing every unresolved reference, instead of leaving placeholders in the output
+        #[arg(long, default_value_t = false)]
+        strict: bool,
+        /// Write a JSON report of every placeholder and whether it resolved (never includes values)
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// Record the id and revisionDate of every referenced secret (never values) in this file
+        #[arg(long, value_name = "FILE")]
+        lock: Option<PathBuf>,
+        /// Check the secrets against --lock instead of writing it, failing if any changed
+        #[arg(long, requires = "lock", default_value_t = false)]
+        frozen: bool,
+        /// Only validate the templates: every key must exist exactly once and every placeholder
+        /// must be well formed. Uses one list call and reads no secret values
+        #[arg(long, default_value_t = false, conflicts_with_all = ["lock", "report", "strict"])]
+        check: bool,
+        /// Output of --check
+        #[arg(long, value_enum, default_value_t = CheckFormat::Text, requires = "check")]
+        check_format: CheckFormat,
+        #[command(flatten)]
+        recursive: RecursiveArgs,
+        #[command(flatten)]
+        env: EnvArgs,
+        /// Quote and escape values for this syntax (detected from the file extension by default)
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// Template language: bws:// placeholders, or a MiniJinja template with secret(),
+        /// secret_field(), secrets(prefix=) and env() functions
+        #[arg(long, value_enum, default_value_t = Engine::Bws)]
+        engine: Engine,
+    },
+    /// Run a command with bws:// references in its environment resolved
+    /// (e.g. run --env DB_PASS=bws://harbor/pg_pass --env-file app.env -- ./server)
+    Run {
+        /// Set a variable for the command; the value may contain bws:// references
+        #[arg(long, value_name = "NAME=VALUE")]
+        env: Vec<String>,
+        /// Load variables from a dotenv file; values may contain bws:// references
+        #[arg(long = "env-file", value_name = "FILE")]
+        env_files: Vec<PathBuf>,
+        /// Conceal resolved secret values (and their base64/URL-encoded forms) in the command's output
+        #[arg(long, default_value_t = false)]
+        mask: bool,
+        /// Command and arguments to run
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Copy stdin to stdout, concealing the values of the given secrets
+    Mask {
+        /// Secret ids to conceal (comma separated)
+        #[arg(long)]
+        ids: Option<String>,
+        /// Secret keys to conceal (comma separated, looked up via list)
+        #[arg(long)]
+        keys: Option<String>,
+    },
+    /// Serve get, get-by-key and render requests over a Unix socket, keeping the connection and
+    /// an in-memory cache (entries live for --cache-ttl seconds). Other commands use it when
+    /// BWS_AGENT_SOCK is set
+    Agent {
+        /// Socket path (defaults to $XDG_RUNTIME_DIR/bws-connector/agent.sock)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Parse the configured access token locally and show the headers that would be sent,
+    /// without contacting the sdk-server
+    Whoami,
+    /// Check step by step that the sdk-server can be used: DNS, connection, TLS and certificates,
+    /// authentication and access to the organization's secrets
+    Doctor,
+}
+
+/// Parse an `IN:OUT` pair for `render --file`
+fn parse_file_pair(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    match s.split_once(':') {
+        Some((input, output)) if !input.is_empty() && !output.is_empty() => {
+            Ok((input.into(), output.into()))
+        }
+        _ => Err("expected IN:OUT".to_string()),
+    }
+}
+
+/// Parse an octal file mode such as `0640`
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid file mode '{}', expected octal such as 0640", s))
 }
@@ -1,23 +1,75 @@
 use anyhow::Context;
 use reqwest::header::HeaderMap;
-use std::path::PathBuf;
+use reqwest::{Method, NoProxy, Proxy};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-/// Build a reqwest client with optional TLS customization
-pub fn build_client(insecure: bool, ca_cert: &Option<PathBuf>) -> anyhow::Result<reqwest::Client> {
-    let mut client_builder = reqwest::Client::builder();
-    
-    if insecure {
-        client_builder = client_builder.danger_accept_invalid_certs(true);
+use crate::agent::{self, AgentRequest};
+use crate::cache::{CacheMode, CacheSettings, SecretCache};
+use crate::error::BwsError;
+use crate::models::{
+    SecretCreateRequest, SecretGetRequest, SecretIdentifiersResponse, SecretPutRequest,
+    SecretResponse, SecretsDeleteRequest, SecretsDeleteResponse, SecretsGetRequest,
+    SecretsResponse,
+};
+use crate::tls::{self, TlsSettings};
+use crate::token::{TokenFile, TokenSource};
+
+/// Connection settings for the sdk-server, mirroring the global CLI flags
+#[derive(Clone, Debug, Default)]
+pub struct ClientSettings {
+    /// Base URL of the sdk-server REST API (e.g. http://localhost:9998/rest/api/1)
+    pub base_url: String,
+    /// Where the Warden access token is read from (falls back to WARDEN_ACCESS_TOKEN)
+    pub token: Option<TokenSource>,
+    pub api_url: Option<String>,
+    pub identity_url: Option<String>,
+    pub state_path: Option<String>,
+    /// Certificates trusted and presented, and pins
+    pub tls: TlsSettings,
+    /// Proxy for every request (`http://`, `https://`, `socks5://` or `socks5h://`); without it,
+    /// the HTTP_PROXY, HTTPS_PROXY and ALL_PROXY variables apply
+    pub proxy: Option<String>,
+    /// Comma-separated hosts, domains and CIDR ranges that bypass `proxy` (defaults to NO_PROXY)
+    pub no_proxy: Option<String>,
+    /// Limit on establishing a connection, including the TLS handshake
+    pub connect_timeout: Option<Duration>,
+    /// Limit on a whole request, from connecting to reading the body
+    pub timeout: Option<Duration>,
+    /// Opt-in on-disk secret cache
+    pub cache: Option<CacheSettings>,
+}
+
+/// Build the reqwest client: the shared rustls config from [`tls::client_config`], the proxy and
+/// the timeouts
+pub fn build_client(settings: &ClientSettings) -> anyhow::Result<reqwest::Client> {
+    let tls = tls::client_config(&settings.tls)?;
+    let mut client_builder = reqwest::Client::builder().use_preconfigured_tls(tls);
+
+    if let Some(url) = &settings.proxy {
+        let no_proxy = match &settings.no_proxy {
+            Some(list) => NoProxy::from_string(list),
+            None => NoProxy::from_env(),
+        };
+        let proxy = Proxy::all(url)
+            .with_context(|| format!("invalid proxy URL '{}'", url))?
+            .no_proxy(no_proxy);
+        client_builder = client_builder.proxy(proxy);
+    }
+    if let Some(timeout) = settings.connect_timeout {
+        client_builder = client_builder.connect_timeout(timeout);
     }
-    
-    if let Some(ca_path) = ca_cert {
-        let pem = std::fs::read(ca_path).context("reading ca cert file")?;
-        let certs = reqwest::Certificate::from_pem(&pem)
-            .context("failed to parse CA certificate PEM")?;
-        client_builder = client_builder.add_root_certificate(certs);
+    if let Some(timeout) = settings.timeout {
+        client_builder = client_builder.timeout(timeout);
     }
-    
-    client_builder.build().context("failed to build http client")
+
+    client_builder
+        .build()
+        .context("failed to build http client")
 }
 
 /// Build headers for Warden authentication and configuration
@@ -28,13 +80,13 @@ pub fn build_headers(
     state_path: Option<String>,
 ) -> anyhow::Result<HeaderMap> {
     let mut headers = HeaderMap::new();
-    
+
     let token = access_token
         .or_else(|| std::env::var("WARDEN_ACCESS_TOKEN").ok())
-        .context("access token missing; set --access-token or WARDEN_ACCESS_TOKEN")?;
-    
+        .context("access token missing; set WARDEN_ACCESS_TOKEN, an --access-token* flag or a token source in the profile")?;
+
     headers.insert("Warden-Access-Token", token.parse()?);
-    
+
     if let Some(u) = api_url {
         headers.insert("Warden-Api-Url", u.parse()?);
     }
@@ -44,6 +96,477 @@ pub fn build_headers(
     if let Some(s) = state_path {
         headers.insert("Warden-State-Path", s.parse()?);
     }
-    
+
     Ok(headers)
 }
+
+/// Typed client for the bitwarden-sdk-server secret endpoints
+#[derive(Clone)]
+pub struct BwsClient {
+    http: reqwest::Client,
+    headers: HeaderMap,
+    base_url: String,
+    verbose: bool,
+    cache: Option<Arc<SecretCache>>,
+    /// Socket of an agent that serves read requests instead of the sdk-server
+    agent: Option<PathBuf>,
+    /// Token file read again when it changes, replacing the token in `headers`
+    token_file: Option<Arc<TokenFile>>,
+}
+
+impl BwsClient {
+    /// Create a client from an existing reqwest client and Warden headers
+    pub fn new(http: reqwest::Client, headers: HeaderMap, base_url: &str) -> Self {
+        Self {
+            http,
+            headers,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            verbose: false,
+            cache: None,
+            agent: None,
+            token_file: None,
+        }
+    }
+
+    /// A client that forwards read requests to the agent listening on `socket`.
+    ///
+    /// The agent holds the access token and connection, so no credentials are needed here;
+    /// create, update and delete are not available through it.
+    pub fn from_agent(socket: impl Into<PathBuf>) -> Self {
+        let mut client = Self::new(reqwest::Client::new(), HeaderMap::new(), "");
+        client.agent = Some(socket.into());
+        client
+    }
+
+    /// Build the http client and headers from connection settings
+    pub fn from_settings(settings: &ClientSettings) -> anyhow::Result<Self> {
+        let http = build_client(settings)?;
+        let access_token = settings.token.as_ref().map(TokenSource::read).transpose()?;
+        let headers = build_headers(
+            access_token,
+            settings.api_url.clone(),
+            settings.identity_url.clone(),
+            settings.state_path.clone(),
+        )?;
+        let cache = match &settings.cache {
+            Some(cache_settings) => {
+                let token = headers["Warden-Access-Token"]
+                    .to_str()
+                    .context("access token is not valid text")?;
+                Some(SecretCache::open(cache_settings, token)?)
+            }
+            None => None,
+        };
+        let mut client = Self::new(http, headers, &settings.base_url);
+        if let Some(cache) = cache {
+            client = client.with_cache(cache);
+        }
+        Ok(client)
+    }
+
+    /// Serve list results and secret values through an on-disk cache
+    pub fn with_cache(mut self, cache: SecretCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Send the token from `path`, reading the file again whenever it changes, for long-lived
+    /// clients such as the agent
+    pub fn with_token_file(mut self, path: &Path) -> anyhow::Result<Self> {
+        self.token_file = Some(Arc::new(TokenFile::open(path)?));
+        Ok(self)
+    }
+
+    /// Print raw list responses and found keys to stderr
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Socket of the agent this client forwards to, if any
+    pub fn agent(&self) -> Option<&Path> {
+        self.agent.as_deref()
+    }
+
+    /// Get a secret by id
+    pub async fn get(&self, id: &str) -> Result<SecretResponse, BwsError> {
+        if let Some(socket) = &self.agent {
+            return agent::call(socket, &AgentRequest::Get { id: id.to_string() }).await;
+        }
+        let req = SecretGetRequest { id: id.to_string() };
+        self.cached(&secret_entry(id), self.send(Method::GET, "secret", &req))
+            .await
+    }
+
+    /// Get a secret by key, looking up its id via the list endpoint
+    pub async fn get_by_key(
+        &self,
+        key: &str,
+        organization_id: &str,
+    ) -> Result<SecretResponse, BwsError> {
+        if let Some(socket) = &self.agent {
+            let req = AgentRequest::GetByKey {
+                key: key.to_string(),
+                organization_id: organization_id.to_string(),
+            };
+            return agent::call(socket, &req).await;
+        }
+        let list = self.list(organization_id).await?;
+        let ids: Vec<String> = list
+            .data
+            .into_iter()
+            .filter(|i| i.key == key)
+            .map(|i| i.id)
+            .collect();
+        match ids.as_slice() {
+            [] => Err(BwsError::KeyNotFound {
+                key: key.to_string(),
+            }),
+            [id] => self.get(id).await,
+            _ => Err(BwsError::AmbiguousKey {
+                key: key.to_string(),
+                ids,
+            }),
+        }
+    }
+
+    /// List secret identifiers in an organization
+    pub async fn list(&self, organization_id: &str) -> Result<SecretIdentifiersResponse, BwsError> {
+        if let Some(socket) = &self.agent {
+            let req = AgentRequest::List {
+                organization_id: organization_id.to_string(),
+            };
+            return agent::call(socket, &req).await;
+        }
+        let list = self
+            .cached(
+                &list_entry(organization_id),
+                self.fetch_list(organization_id),
+            )
+            .await?;
+        if self.verbose {
+            let keys: Vec<&str> = list.data.iter().map(|i| i.key.as_str()).collect();
+            eprintln!("found keys: {:?}", keys);
+        }
+        Ok(list)
+    }
+
+    async fn fetch_list(
+        &self,
+        organization_id: &str,
+    ) -> Result<SecretIdentifiersResponse, BwsError> {
+        let txt = self
+            .send_raw(
+                Method::GET,
+                "secrets",
+                &serde_json::json!({"OrganizationID": organization_id}),
+            )
+            .await?;
+        if self.verbose {
+            eprintln!("raw list response: {}", txt);
+        }
+        serde_json::from_str(&txt).map_err(|source| BwsError::InvalidResponse {
+            what: "list".to_string(),
+            source,
+        })
+    }
+
+    /// Get several secrets by id in one request. With a cache, only ids without a usable
+    /// cached copy are fetched.
+    pub async fn get_by_ids(&self, ids: Vec<String>) -> Result<SecretsResponse, BwsError> {
+        if let Some(socket) = &self.agent {
+            return agent::call(socket, &AgentRequest::GetByIds { ids }).await;
+        }
+        let Some(cache) = &self.cache else {
+            return self.fetch_by_ids(ids).await;
+        };
+
+        let mut data = Vec::with_capacity(ids.len());
+        let mut stale = Vec::new();
+        let mut missing = Vec::new();
+        for id in ids {
+            match cache.get::<SecretResponse>(&secret_entry(&id)) {
+                Some(hit) if hit.fresh => data.push(hit.value),
+                Some(hit) => {
+                    stale.push(hit.value);
+                    missing.push(id);
+                }
+                None => missing.push(id),
+            }
+        }
+        if missing.is_empty() {
+            return Ok(SecretsResponse { data });
+        }
+
+        let fallback = |err: BwsError| {
+            if stale.len() < missing.len() {
+                return Err(err);
+            }
+            eprintln!(
+                "warning: {}; using {} stale cached secret(s)",
+                err,
+                stale.len()
+            );
+            Ok(stale.clone())
+        };
+        match cache.mode() {
+            CacheMode::Offline => data.extend(fallback(BwsError::CacheMiss {
+                what: format!("{} secret(s)", missing.len() - stale.len()),
+            })?),
+            mode => match self.fetch_by_ids(missing.clone()).await {
+                Ok(resp) => {
+                    for secret in &resp.data {
+                        self.store(&secret_entry(&secret.id), secret);
+                    }
+                    data.extend(resp.data);
+                }
+                Err(err) if mode == CacheMode::StaleIfError && err.is_unavailable() => {
+                    data.extend(fallback(err)?)
+                }
+                Err(err) => return Err(err),
+            },
+        }
+        Ok(SecretsResponse { data })
+    }
+
+    async fn fetch_by_ids(&self, ids: Vec<String>) -> Result<SecretsResponse, BwsError> {
+        let req = SecretsGetRequest { ids };
+        self.send(Method::GET, "secrets-by-ids", &req).await
+    }
+
+    /// Create a secret
+    pub async fn create(&self, req: &SecretCreateRequest) -> Result<SecretResponse, BwsError> {
+        let secret: SecretResponse = self.send(Method::POST, "secret", req).await?;
+        self.invalidate_list(&secret);
+        Ok(secret)
+    }
+
+    /// Update an existing secret
+    pub async fn update(&self, req: &SecretPutRequest) -> Result<SecretResponse, BwsError> {
+        let secret: SecretResponse = self.send(Method::PUT, "secret", req).await?;
+        self.invalidate_list(&secret);
+        Ok(secret)
+    }
+
+    /// Delete secrets by id
+    pub async fn delete(&self, ids: Vec<String>) -> Result<SecretsDeleteResponse, BwsError> {
+        let req = SecretsDeleteRequest { ids };
+        let resp = self.send(Method::DELETE, "secret", &req).await?;
+        if let Some(cache) = &self.cache {
+            req.ids
+                .iter()
+                .for_each(|id| cache.remove(&secret_entry(id)));
+            // the organizations of the deleted secrets are unknown, so every cached list goes
+            let orgs = cache.get::<Vec<String>>(LISTS_ENTRY);
+            for org in orgs.map(|hit| hit.value).unwrap_or_default() {
+                cache.remove(&list_entry(&org));
+            }
+            cache.remove(LISTS_ENTRY);
+        }
+        Ok(resp)
+    }
+
+    /// Serve `name` from the cache when possible, otherwise run `fetch` and cache its result.
+    ///
+    /// Stale entries are used when offline, or with stale-if-error when `fetch` fails because
+    /// the sdk-server is unavailable.
+    async fn cached<T, F>(&self, name: &str, fetch: F) -> Result<T, BwsError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, BwsError>>,
+    {
+        let Some(cache) = &self.cache else {
+            return fetch.await;
+        };
+        let hit = cache.get::<T>(name);
+        match (cache.mode(), hit) {
+            (_, Some(hit)) if hit.fresh => Ok(hit.value),
+            (CacheMode::Offline, Some(hit)) => Ok(hit.value),
+            (CacheMode::Offline, None) => Err(BwsError::CacheMiss {
+                what: name.to_string(),
+            }),
+            (mode, hit) => match fetch.await {
+                Ok(value) => {
+                    self.store(name, &value);
+                    Ok(value)
+                }
+                Err(err) if mode == CacheMode::StaleIfError && err.is_unavailable() => match hit {
+                    Some(hit) => {
+                        eprintln!("warning: {}; using stale cached {}", err, name);
+                        Ok(hit.value)
+                    }
+                    None => Err(err),
+                },
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    fn store<T: Serialize>(&self, name: &str, value: &T) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        if let Err(e) = cache.put(name, value) {
+            eprintln!("warning: {:#}", e);
+        }
+        // cache file names are hashed, so the cached lists are recorded to find them again
+        if let Some(org) = name.strip_prefix("list:") {
+            let mut orgs = cache
+                .get::<Vec<String>>(LISTS_ENTRY)
+                .map(|hit| hit.value)
+                .unwrap_or_default();
+            if !orgs.iter().any(|o| o == org) {
+                orgs.push(org.to_string());
+                if let Err(e) = cache.put(LISTS_ENTRY, &orgs) {
+                    eprintln!("warning: {:#}", e);
+                }
+            }
+        }
+    }
+
+    /// Store a changed secret and drop the list it belongs to
+    fn invalidate_list(&self, secret: &SecretResponse) {
+        if let Some(cache) = &self.cache {
+            cache.remove(&list_entry(&secret.organization_id));
+            self.store(&secret_entry(&secret.id), secret);
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<T, BwsError> {
+        let txt = self.send_raw(method, path, body).await?;
+        serde_json::from_str(&txt).map_err(|source| BwsError::InvalidResponse {
+            what: path.to_string(),
+            source,
+        })
+    }
+
+    /// The Warden headers, with the current token when it comes from a watched file
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.headers.clone();
+        if let Some(file) = &self.token_file {
+            headers.insert("Warden-Access-Token", file.current());
+        }
+        headers
+    }
+
+    /// Send a request and return the body of a successful response
+    async fn send_raw(
+        &self,
+        method: Method,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<String, BwsError> {
+        let url = format!("{}/{}", self.base_url, path);
+        let resp = self
+            .http
+            .request(method, &url)
+            .headers(self.headers())
+            .json(body)
+            .send()
+            .await?;
+        let status = resp.status();
+        let txt = resp.text().await?;
+        if !status.is_success() {
+            return Err(BwsError::from_response(status, txt));
+        }
+        Ok(txt)
+    }
+}
+
+/// Cache entry naming the organizations with a cached list
+const LISTS_ENTRY: &str = "lists";
+
+fn secret_entry(id: &str) -> String {
+    format!("secret:{}", id)
+}
+
+fn list_entry(organization_id: &str) -> String {
+    format!("list:{}", organization_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheMode, CacheSettings};
+    use crate::mock;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_delete_invalidates_cache_after_success() {
+        let secrets = Arc::new(Mutex::new(vec![
+            mock::secret_json("id-a", "a", "1"),
+            mock::secret_json("id-b", "b", "2"),
+        ]));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (state, count) = (secrets.clone(), requests.clone());
+        let port = mock::serve(move |req| {
+            count.fetch_add(1, Ordering::SeqCst);
+            let mut secrets = state.lock().unwrap();
+            match (req.method.as_str(), req.path.as_str()) {
+                ("GET", "secrets") => {
+                    let list: Vec<_> = secrets
+                        .iter()
+                        .map(|s| serde_json::json!({"id": s["id"], "key": s["key"], "organizationId": "org"}))
+                        .collect();
+                    ("200 OK", serde_json::json!({ "data": list }).to_string())
+                }
+                ("GET", "secret") => {
+                    let secret = secrets.iter().find(|s| s["id"] == req.body["ID"]);
+                    ("200 OK", secret.cloned().unwrap_or_default().to_string())
+                }
+                // deleting "id-b" fails
+                ("DELETE", "secret") if req.body["IDS"][0] == "id-b" => {
+                    ("500 Internal Server Error", "database unavailable".to_string())
+                }
+                ("DELETE", "secret") => {
+                    secrets.retain(|s| s["id"] != req.body["IDS"][0]);
+                    let data = serde_json::json!([{"id": req.body["IDS"][0], "error": null}]);
+                    ("200 OK", serde_json::json!({ "data": data }).to_string())
+                }
+                _ => ("400 Bad Request", "unexpected request".to_string()),
+            }
+        })
+        .await;
+        // on disk, where cached lists can only be found through the "lists" entry
+        let dir = std::env::temp_dir().join(format!("bws-client-test-{}", std::process::id()));
+        let settings = CacheSettings {
+            dir: Some(dir.clone()),
+            ttl: Duration::from_secs(300),
+            mode: CacheMode::Normal,
+        };
+        let client = mock::client(port).with_cache(SecretCache::open(&settings, "token").unwrap());
+
+        assert_eq!(client.list("org").await.unwrap().data.len(), 2);
+        client.get("id-b").await.unwrap();
+        let before = requests.load(Ordering::SeqCst);
+        // a failed delete keeps the cache
+        assert!(client.delete(vec!["id-b".into()]).await.is_err());
+        client.get("id-b").await.unwrap();
+        assert_eq!(client.list("org").await.unwrap().data.len(), 2);
+        assert_eq!(requests.load(Ordering::SeqCst), before + 1);
+
+        // a successful delete drops the secret and the cached list
+        client.delete(vec!["id-a".into()]).await.unwrap();
+        let keys: Vec<String> = client
+            .list("org")
+            .await
+            .unwrap()
+            .data
+            .into_iter()
+            .map(|i| i.key)
+            .collect();
+        assert_eq!(keys, ["b"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
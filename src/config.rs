@@ -0,0 +1,221 @@
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::token::TokenSource;
+
+/// `~/.config/bws-connector/config.toml`: named profiles of connection settings.
+///
+/// ```toml
+/// default_profile = "prod"
+///
+/// [profiles.prod]
+/// base_url = "https://bws.example.com/rest/api/1"
+/// organization_id = "9f2c..."
+/// ca_cert = ["prod-ca.pem", "corp-proxy-ca.pem"]
+/// client_cert = "client.p12"
+/// proxy = "socks5h://bastion:1080"
+/// access_token_file = "/var/run/secrets/bws/token"
+/// ```
+///
+/// Every field of a profile is optional; command-line flags and environment variables override it.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Profile used when `--profile` and `BWS_PROFILE` are not set
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// The file this was loaded from, for error messages
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub base_url: Option<String>,
+    pub organization_id: Option<String>,
+    pub api_url: Option<String>,
+    pub identity_url: Option<String>,
+    pub state_path: Option<String>,
+    pub insecure: Option<bool>,
+    /// One CA bundle or a list of them. Relative paths here, in `client_cert`, `client_key` and
+    /// `access_token_file` are taken from the config file's directory
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub ca_cert: Vec<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub client_cert_password: Option<String>,
+    /// `sha256//<base64>` pins, as given to `--pin-sha256`
+    #[serde(default)]
+    pub pin_sha256: Vec<String>,
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    /// Seconds, as given to `--connect-timeout`
+    pub connect_timeout: Option<u64>,
+    /// Seconds, as given to `--timeout`
+    pub timeout: Option<u64>,
+    /// Environment variable holding this profile's access token
+    pub access_token_env: Option<String>,
+    /// File holding the access token
+    pub access_token_file: Option<PathBuf>,
+    /// Shell command printing the access token
+    pub access_token_command: Option<String>,
+    /// Keyring entry holding the access token
+    pub access_token_keyring: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        let mut config: Self = toml::from_str(&text)
+            .with_context(|| format!("parsing config file {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for profile in config.profiles.values_mut() {
+            let files = profile.ca_cert.iter_mut().chain(
+                [
+                    &mut profile.client_cert,
+                    &mut profile.client_key,
+                    &mut profile.access_token_file,
+                ]
+                .into_iter()
+                .flatten(),
+            );
+            for file in files {
+                if file.is_relative() {
+                    *file = dir.join(&*file);
+                }
+            }
+        }
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Load `path` if it exists, or an empty config without profiles
+    pub fn load_or_default(path: &Path) -> anyhow::Result<Self> {
+        match path.exists() {
+            true => Self::load(path),
+            false => Ok(Self::default()),
+        }
+    }
+
+    /// The profile called `name`, or the default profile. Without either, an empty profile.
+    pub fn profile(&self, name: Option<&str>) -> anyhow::Result<Profile> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(Profile::default());
+        };
+        self.profiles
+            .get(name)
+            .cloned()
+            .with_context(|| match &self.path {
+                Some(path) => format!("no profile '{}' in {}", name, path.display()),
+                None => format!("no profile '{}': config file not found", name),
+            })
+    }
+}
+
+impl Profile {
+    /// Where this profile's access token comes from. `access_token_env` only counts when the
+    /// variable is set; naming more than one source is an error.
+    pub fn token_source(&self) -> anyhow::Result<Option<TokenSource>> {
+        let env = self
+            .access_token_env
+            .as_ref()
+            .and_then(|var| std::env::var(var).ok())
+            .filter(|v| !v.is_empty());
+        let sources: Vec<TokenSource> = [
+            env.map(TokenSource::Value),
+            self.access_token_file.clone().map(TokenSource::File),
+            self.access_token_command.clone().map(TokenSource::Command),
+            self.access_token_keyring.clone().map(TokenSource::Keyring),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        anyhow::ensure!(
+            sources.len() <= 1,
+            "a profile can set only one of access_token_env, access_token_file, access_token_command and access_token_keyring"
+        );
+        Ok(sources.into_iter().next())
+    }
+}
+
+/// A single value or a list of them
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
+/// `$XDG_CONFIG_HOME/bws-connector/config.toml`, falling back to `~/.config/bws-connector/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(base.join("bws-connector").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let dir = std::env::temp_dir().join(format!("bws-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "default_profile = \"dev\"\n\
+             [profiles.dev]\n\
+             base_url = \"http://127.0.0.1:9998/rest/api/1\"\n\
+             [profiles.prod]\n\
+             organization_id = \"org-prod\"\n\
+             insecure = false\n\
+             ca_cert = \"certs/ca.pem\"\n\
+             [profiles.corp]\n\
+             ca_cert = [\"/etc/ssl/corp.pem\", \"extra.pem\"]\n\
+             client_cert = \"client.p12\"\n",
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+
+        let dev = config.profile(None).unwrap();
+        assert_eq!(dev.base_url.unwrap(), "http://127.0.0.1:9998/rest/api/1");
+        let prod = config.profile(Some("prod")).unwrap();
+        assert_eq!(prod.organization_id.unwrap(), "org-prod");
+        assert_eq!(prod.ca_cert, [dir.join("certs/ca.pem")]);
+        let corp = config.profile(Some("corp")).unwrap();
+        assert_eq!(
+            corp.ca_cert,
+            [PathBuf::from("/etc/ssl/corp.pem"), dir.join("extra.pem")]
+        );
+        assert_eq!(corp.client_cert.unwrap(), dir.join("client.p12"));
+        let err = config.profile(Some("staging")).unwrap_err().to_string();
+        assert!(err.starts_with("no profile 'staging' in "), "{}", err);
+
+        std::fs::write(&path, "[profiles.dev]\nbase_uri = \"typo\"\n").unwrap();
+        assert!(Config::load(&path).is_err());
+        std::fs::write(
+            &path,
+            "[profiles.ci]\naccess_token_file = \"token\"\naccess_token_command = \"pass bws\"\n",
+        )
+        .unwrap();
+        let ci = Config::load(&path).unwrap().profile(Some("ci")).unwrap();
+        assert!(ci.token_source().is_err());
+        let missing = Config::load_or_default(&dir.join("missing.toml")).unwrap();
+        assert_eq!(missing.profile(None).unwrap(), Profile::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,662 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::client::{BwsClient, ClientSettings};
+use crate::error::BwsError;
+use crate::tls;
+use crate::token::{AccessToken, TokenSource};
+
+/// How long each network step may take
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Certificates expiring within this many days are a warning
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    /// Not run because an earlier step failed, or not applicable
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "pass",
+            Self::Warn => "warn",
+            Self::Fail => "FAIL",
+            Self::Skip => "skip",
+        })
+    }
+}
+
+/// One step of `doctor`
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// What to change when the step did not pass
+    pub hint: Option<String>,
+}
+
+/// Result of [`diagnose`], printed as a table
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// No step failed (warnings are allowed)
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != Status::Fail)
+    }
+
+    fn push(&mut self, name: &'static str, status: Status, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail: detail.into(),
+            hint: None,
+        });
+    }
+
+    fn fail(&mut self, name: &'static str, detail: impl Into<String>, hint: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        });
+    }
+
+    /// Mark the remaining steps as not run
+    fn skip(mut self, names: &[&'static str], reason: &str) -> Self {
+        for name in names {
+            self.push(name, Status::Skip, format!("skipped: {}", reason));
+        }
+        self
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for check in &self.checks {
+            let mut lines = check.detail.lines();
+            writeln!(
+                f,
+                "{:width$}  {}  {}",
+                check.name,
+                check.status,
+                lines.next().unwrap_or_default()
+            )?;
+            for line in lines {
+                writeln!(f, "{:width$}        {}", "", line)?;
+            }
+            if let Some(hint) = &check.hint {
+                writeln!(f, "{:width$}        hint: {}", "", hint)?;
+            }
+        }
+        let failed = self
+            .checks
+            .iter()
+            .filter(|c| c.status == Status::Fail)
+            .count();
+        write!(f, "{} check(s), {} failed", self.checks.len(), failed)
+    }
+}
+
+/// Check step by step what a request needs: a well-formed token, the base URL's host resolving,
+/// a TCP connection, the TLS handshake with the configured trust settings and the server's
+/// certificate chain, an authenticated list call, and secrets visible in the organization.
+/// With `--proxy`, only the requests go through the proxy.
+///
+/// Network failures stop the remaining network steps, which are reported as skipped.
+pub async fn diagnose(settings: &ClientSettings, org_id: Option<&str>) -> Report {
+    let mut report = Report::default();
+
+    // read once, so a command or stdin token is not asked for twice
+    let token = match settings.token.as_ref().map(TokenSource::read) {
+        None => {
+            report.fail(
+                "access token",
+                "no access token configured",
+                "set WARDEN_ACCESS_TOKEN, an --access-token* flag or a token source in the profile",
+            );
+            None
+        }
+        Some(Err(e)) => {
+            report.fail(
+                "access token",
+                format!("{:#}", e),
+                "check the token source; `whoami` reads it the same way",
+            );
+            None
+        }
+        Some(Ok(token)) => {
+            match AccessToken::parse(&token) {
+                Ok(parsed) => report.push(
+                    "access token",
+                    Status::Pass,
+                    format!("id {} ({})", parsed.access_token_id, parsed.fingerprint),
+                ),
+                Err(e) => report.fail(
+                    "access token",
+                    e.to_string(),
+                    "use the whole machine-account access token, as shown once when it was created",
+                ),
+            }
+            Some(token)
+        }
+    };
+
+    let network = ["dns", "connect", "tls", "auth", "organization"];
+    let url = match reqwest::Url::parse(&settings.base_url) {
+        Ok(url) if url.host_str().is_some() => url,
+        Ok(_) | Err(_) => {
+            report.fail(
+                "base url",
+                format!("'{}' is not a valid URL", settings.base_url),
+                "pass --base-url like http://127.0.0.1:9998/rest/api/1",
+            );
+            return report.skip(&network, "no valid base URL");
+        }
+    };
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    report.push("base url", Status::Pass, url.as_str());
+    if let Some(proxy) = &settings.proxy {
+        report.checks.push(Check {
+            name: "proxy",
+            status: Status::Warn,
+            detail: format!("requests go through {}", proxy),
+            hint: Some(
+                "dns, connect and tls are checked directly, without the proxy; auth uses it".into(),
+            ),
+        });
+    }
+
+    // dns
+    let addrs: Vec<SocketAddr> =
+        match tokio::time::timeout(STEP_TIMEOUT, tokio::net::lookup_host((host.as_str(), port)))
+            .await
+        {
+            Ok(Ok(addrs)) => addrs.collect(),
+            Ok(Err(e)) => {
+                report.fail(
+                    "dns",
+                    format!("cannot resolve {}: {}", host, e),
+                    "check the host name in --base-url and the DNS servers of this machine",
+                );
+                return report.skip(&network[1..], "dns failed");
+            }
+            Err(_) => {
+                report.fail(
+                    "dns",
+                    format!("resolving {} timed out", host),
+                    "check the DNS servers of this machine",
+                );
+                return report.skip(&network[1..], "dns failed");
+            }
+        };
+    let listed: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+    report.push(
+        "dns",
+        Status::Pass,
+        format!("{} -> {}", host, listed.join(", ")),
+    );
+
+    // connect
+    let mut connected = None;
+    let mut errors = Vec::new();
+    for addr in &addrs {
+        match tokio::time::timeout(STEP_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => {
+                connected = Some((*addr, stream));
+                break;
+            }
+            Ok(Err(e)) => errors.push(format!("{}: {}", addr, e)),
+            Err(_) => errors.push(format!("{}: timed out", addr)),
+        }
+    }
+    let Some((addr, stream)) = connected else {
+        report.fail(
+            "connect",
+            errors.join("\n"),
+            format!(
+                "is the sdk-server running and listening on port {}? check --base-url and firewalls",
+                port
+            ),
+        );
+        return report.skip(&network[2..], "no connection");
+    };
+    report.push("connect", Status::Pass, format!("connected to {}", addr));
+
+    // tls
+    if url.scheme() == "https" {
+        if !check_tls(&mut report, settings, &host, addr, stream).await {
+            return report.skip(&network[3..], "tls failed");
+        }
+    } else if addr.ip().is_loopback() {
+        // close it, so a single-threaded server can answer the requests below
+        drop(stream);
+        report.push("tls", Status::Skip, "plain http to a local server");
+    } else {
+        drop(stream);
+        report.checks.push(Check {
+            name: "tls",
+            status: Status::Warn,
+            detail: format!(
+                "plain http to {}: the access token is sent unencrypted",
+                host
+            ),
+            hint: Some("use an https base URL, or run the sdk-server next to this tool".into()),
+        });
+    }
+
+    // auth
+    let Some(token) = token else {
+        return report.skip(&network[3..], "no access token");
+    };
+    let settings = ClientSettings {
+        token: Some(TokenSource::Value(token)),
+        cache: None,
+        ..settings.clone()
+    };
+    let client = match BwsClient::from_settings(&settings) {
+        Ok(client) => client,
+        Err(e) => {
+            report.fail(
+                "auth",
+                format!("{:#}", e),
+                "check --ca-cert and the header flags",
+            );
+            return report.skip(&network[4..], "no client");
+        }
+    };
+    // auth, with the list call the organization check reads
+    let Some(org_id) = org_id else {
+        report.push("auth", Status::Skip, "no organization id to list");
+        report.fail(
+            "organization",
+            "no organization id",
+            "set --organization-id, WARDEN_ORGANIZATION_ID or organization_id in the profile",
+        );
+        return report;
+    };
+    let list = match timed(client.list(org_id)).await {
+        None => {
+            report.fail(
+                "auth",
+                "request timed out",
+                "the server accepted the connection but did not answer: is it the sdk-server?",
+            );
+            return report.skip(&network[4..], "request failed");
+        }
+        Some(Err(e @ BwsError::Unauthorized { .. })) => {
+            report.fail(
+                "auth",
+                e.to_string(),
+                "the token was rejected: it may be revoked or expired, or --identity-url and \
+                 --api-url may point at another Bitwarden region",
+            );
+            return report.skip(&network[4..], "not authenticated");
+        }
+        // the server got past the login and turned down the request itself
+        Some(Err(e @ (BwsError::BadRequest { .. } | BwsError::NotFound { .. }))) => {
+            report.push("auth", Status::Pass, "the sdk-server accepted the token");
+            Err(e)
+        }
+        Some(Err(e)) => {
+            report.fail("auth", e.to_string(), request_hint(&e));
+            return report.skip(&network[4..], "request failed");
+        }
+        Some(Ok(list)) => {
+            report.push("auth", Status::Pass, "the sdk-server accepted the token");
+            Ok(list)
+        }
+    };
+
+    // organization
+    match list {
+        Ok(list) if list.data.is_empty() => report.fail(
+            "organization",
+            format!("{}: no secrets visible", org_id),
+            "check the organization id, and that the machine account has access to a project with secrets",
+        ),
+        Ok(list) => report.push(
+            "organization",
+            Status::Pass,
+            format!("{}: {} secret(s)", org_id, list.data.len()),
+        ),
+        Err(e) => report.fail("organization", e.to_string(), request_hint(&e)),
+    }
+    report
+}
+
+/// A request with the step timeout; `None` when it timed out
+async fn timed<T>(
+    request: impl std::future::Future<Output = Result<T, BwsError>>,
+) -> Option<Result<T, BwsError>> {
+    tokio::time::timeout(STEP_TIMEOUT, request).await.ok()
+}
+
+fn request_hint(e: &BwsError) -> &'static str {
+    match e {
+        BwsError::NotFound { .. } | BwsError::BadRequest { .. } => {
+            "check the organization id and that the machine account belongs to it"
+        }
+        BwsError::ServerError { .. } => "see the sdk-server logs",
+        BwsError::InvalidResponse { .. } => {
+            "the base URL answered, but not like the sdk-server REST API: check its host, port and /rest/api/1 path"
+        }
+        _ => "the request did not complete; see the checks above",
+    }
+}
+
+/// The TLS handshake and certificate steps; false when no usable connection was made
+async fn check_tls(
+    report: &mut Report,
+    settings: &ClientSettings,
+    host: &str,
+    addr: SocketAddr,
+    stream: TcpStream,
+) -> bool {
+    let config = match tls::client_config(&settings.tls) {
+        Ok(config) => config,
+        Err(e) => {
+            report.fail(
+                "tls",
+                format!("{:#}", e),
+                "check the files given to --ca-cert, --client-cert and --client-key, and --pin-sha256",
+            );
+            return false;
+        }
+    };
+    let Ok(server_name) = rustls::ServerName::try_from(host) else {
+        report.fail(
+            "tls",
+            format!("'{}' is not a valid TLS server name", host),
+            "check the host in --base-url",
+        );
+        return false;
+    };
+
+    let verified = handshake(config, server_name.clone(), stream).await;
+    let certs = match verified {
+        Ok((version, certs)) => {
+            let detail = format!("{}, {}", version, trust_detail(settings));
+            let status = match settings.tls.insecure {
+                true => Status::Warn,
+                false => Status::Pass,
+            };
+            report.checks.push(Check {
+                name: "tls",
+                status,
+                detail,
+                hint: settings
+                    .tls
+                    .insecure
+                    .then(|| "pass --ca-cert instead of --insecure".to_string()),
+            });
+            certs
+        }
+        Err(e) => {
+            let hint = match e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+            {
+                Some(rustls::Error::InvalidCertificate(cert)) => match cert {
+                    rustls::CertificateError::UnknownIssuer
+                    | rustls::CertificateError::BadSignature => {
+                        "the server certificate is not signed by a trusted CA: pass --ca-cert with \
+                         the CA bundle that issued it"
+                    }
+                    rustls::CertificateError::NotValidForName => {
+                        "the certificate does not cover this host: use a name from the certificate \
+                         in --base-url"
+                    }
+                    rustls::CertificateError::Expired => {
+                        "the server certificate has expired: renew it"
+                    }
+                    _ => "the server certificate was rejected",
+                },
+                Some(rustls::Error::General(_)) => {
+                    "the server's keys are not pinned: compare --pin-sha256 with the pins below"
+                }
+                Some(rustls::Error::AlertReceived(
+                    rustls::AlertDescription::CertificateRequired
+                    | rustls::AlertDescription::BadCertificate
+                    | rustls::AlertDescription::UnknownCA
+                    | rustls::AlertDescription::HandshakeFailure,
+                )) => {
+                    "the server rejected the connection: it may require a client certificate \
+                     (--client-cert) issued by a CA it trusts"
+                }
+                _ => "the TLS handshake failed: is this port serving https?",
+            };
+            report.fail("tls", e.to_string(), hint);
+            // connect again without verification or pins, to still show the chain
+            let unverified = tls::TlsSettings {
+                insecure: true,
+                pins: Vec::new(),
+                ..settings.tls.clone()
+            };
+            let Ok(config) = tls::client_config(&unverified) else {
+                return false;
+            };
+            if let Ok(stream) = TcpStream::connect(addr).await
+                && let Ok((_, certs)) = handshake(config, server_name, stream).await
+            {
+                report.checks.push(chain_check(&certs, SystemTime::now()));
+            }
+            return false;
+        }
+    };
+    report.checks.push(chain_check(&certs, SystemTime::now()));
+    true
+}
+
+fn trust_detail(settings: &ClientSettings) -> String {
+    let tls = &settings.tls;
+    let mut detail = match (tls.ca_certs.as_slice(), tls.insecure) {
+        (_, true) => "certificate not verified (--insecure)".to_string(),
+        ([], false) => "verified with the bundled roots".to_string(),
+        (paths, false) => {
+            let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+            format!("verified with {} and the bundled roots", paths.join(", "))
+        }
+    };
+    if !tls.pins.is_empty() {
+        detail.push_str(", key pinned");
+    }
+    if tls.client_cert.is_some() {
+        detail.push_str(", client certificate sent");
+    }
+    detail
+}
+
+async fn handshake(
+    config: rustls::ClientConfig,
+    server_name: rustls::ServerName,
+    stream: TcpStream,
+) -> std::io::Result<(String, Vec<rustls::Certificate>)> {
+    let connector = TlsConnector::from(Arc::new(config));
+    let tls = tokio::time::timeout(STEP_TIMEOUT, connector.connect(server_name, stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))??;
+    let (_, connection) = tls.get_ref();
+    let version = connection
+        .protocol_version()
+        .map_or_else(|| "TLS".to_string(), |v| format!("{:?}", v));
+    let certs = connection.peer_certificates().unwrap_or_default().to_vec();
+    Ok((version, certs))
+}
+
+/// The certificate step: subject, issuer and expiry of each certificate the server sent
+fn chain_check(certs: &[rustls::Certificate], now: SystemTime) -> Check {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let mut lines = Vec::new();
+    let mut status = Status::Pass;
+    let mut hint = None;
+    for (i, cert) in certs.iter().enumerate() {
+        let Ok((_, parsed)) = x509_parser::parse_x509_certificate(&cert.0) else {
+            lines.push(format!("[{}] unparseable certificate", i));
+            continue;
+        };
+        let not_after = parsed.validity().not_after;
+        let days = (not_after.timestamp() - now).div_euclid(86_400);
+        lines.push(format!("[{}] {}", i, parsed.subject()));
+        lines.push(format!("    issuer {}", parsed.issuer()));
+        lines.push(format!("    expires {} ({} days)", not_after, days));
+        if let Some(pin) = tls::spki_sha256(&cert.0) {
+            lines.push(format!("    pin sha256//{}", BASE64.encode(pin)));
+        }
+        if days < 0 {
+            status = Status::Fail;
+            hint = Some("a certificate in the chain has expired: renew it".to_string());
+        } else if days < EXPIRY_WARNING_DAYS && status == Status::Pass {
+            status = Status::Warn;
+            hint = Some(format!(
+                "a certificate expires within {} days: renew it soon",
+                EXPIRY_WARNING_DAYS
+            ));
+        }
+    }
+    if certs.is_empty() {
+        lines.push("the server sent no certificates".to_string());
+    }
+    Check {
+        name: "certificate",
+        status,
+        detail: lines.join("\n"),
+        hint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    const TOKEN: &str = "0.6a3b1c9e-4f2d-4e8a-9b7c-1d2e3f4a5b6c.Zx9yQ2wErT5uIoP1aSdF3gHjK7lM4n:AAECAwQFBgcICQoLDA0ODw==";
+
+    /// A local stand-in for the sdk-server: rejects the token "0.rejected..." and the organization
+    /// "bad-org", and answers the list and secrets-by-ids endpoints
+    async fn mock_server() -> u16 {
+        mock::serve(|req| {
+            if req.headers.contains("warden-access-token: 0.rejected") {
+                (
+                    "400 Bad Request",
+                    r#"failed to login: {"error":"invalid_client"}"#.to_string(),
+                )
+            } else if req.body["OrganizationID"] == "bad-org" {
+                ("400 Bad Request", "organization not found".to_string())
+            } else if req.method == "GET" && req.path == "secrets" {
+                (
+                    "200 OK",
+                    r#"{"data":[{"id":"id-1","key":"harbor","organizationId":"org"}]}"#.to_string(),
+                )
+            } else {
+                ("200 OK", r#"{"data":[]}"#.to_string())
+            }
+        })
+        .await
+    }
+
+    fn settings(port: u16, token: &str) -> ClientSettings {
+        ClientSettings {
+            base_url: format!("http://127.0.0.1:{}/rest/api/1", port),
+            token: Some(TokenSource::Value(token.to_string())),
+            api_url: None,
+            identity_url: None,
+            state_path: None,
+            ..Default::default()
+        }
+    }
+
+    fn statuses(report: &Report) -> Vec<(&str, Status)> {
+        report.checks.iter().map(|c| (c.name, c.status)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_against_mock_server() {
+        let port = mock_server().await;
+        let report = diagnose(&settings(port, TOKEN), Some("org")).await;
+        assert_eq!(
+            statuses(&report),
+            [
+                ("access token", Status::Pass),
+                ("base url", Status::Pass),
+                ("dns", Status::Pass),
+                ("connect", Status::Pass),
+                ("tls", Status::Skip),
+                ("auth", Status::Pass),
+                ("organization", Status::Pass),
+            ]
+        );
+        assert!(report.passed());
+
+        let rejected = TOKEN.replacen("0.", "0.rejected", 1);
+        let report = diagnose(&settings(port, &rejected), Some("org")).await;
+        let found = statuses(&report);
+        assert_eq!(found[0], ("access token", Status::Fail));
+        assert_eq!(
+            found[5..],
+            [("auth", Status::Fail), ("organization", Status::Skip)]
+        );
+        assert!(!report.passed());
+        assert!(report.to_string().contains("hint: the token was rejected"));
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_bad_request_is_not_an_auth_failure() {
+        let port = mock_server().await;
+        let report = diagnose(&settings(port, TOKEN), Some("bad-org")).await;
+        assert_eq!(
+            statuses(&report)[5..],
+            [("auth", Status::Pass), ("organization", Status::Fail)]
+        );
+        assert!(
+            report
+                .to_string()
+                .contains("hint: check the organization id")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_connection_refused() {
+        // bind and drop, so nothing listens on the port
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let report = diagnose(&settings(port, TOKEN), None).await;
+        assert_eq!(
+            statuses(&report)[3..],
+            [
+                ("connect", Status::Fail),
+                ("tls", Status::Skip),
+                ("auth", Status::Skip),
+                ("organization", Status::Skip),
+            ]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use reqwest::StatusCode;
+use serde_json::Value as JsonValue;
+
+/// Errors returned by [`crate::BwsClient`] and the render pipeline.
+///
+/// Each variant maps to a stable process exit code (see [`BwsError::exit_code`]):
+///
+/// | code | variant                              |
+/// |------|--------------------------------------|
+/// | 1    | any other failure                    |
+/// | 2    | invalid command line usage           |
+/// | 3    | `Unauthorized`                       |
+/// | 4    | `NotFound`, `KeyNotFound`            |
+/// | 5    | `AmbiguousKey`                       |
+/// | 6    | `BadRequest`                         |
+/// | 7    | `ServerError`                        |
+/// | 8    | `Transport`, `AgentUnavailable`      |
+/// | 9    | `InvalidResponse`                    |
+/// | 10   | `Unresolved`                         |
+/// | 11   | `CacheMiss`                          |
+/// | 12   | `LockMismatch`                       |
+/// | 13   | `CheckFailed`                        |
+/// | 14   | `Reference`                          |
+/// | 15   | `InvalidToken`                       |
+///
+/// `Agent` carries an error reported by the agent with its original kind and exit code.
+#[derive(Debug, thiserror::Error)]
+pub enum BwsError {
+    #[error("unauthorized (HTTP {status}): {body}")]
+    Unauthorized { status: u16, body: String },
+    #[error("not found (HTTP {status}): {body}")]
+    NotFound { status: u16, body: String },
+    #[error("secret with key '{key}' not found")]
+    KeyNotFound { key: String },
+    #[error("key '{key}' matches {} secrets: {}", ids.len(), ids.join(", "))]
+    AmbiguousKey { key: String, ids: Vec<String> },
+    #[error("bad request (HTTP {status}): {body}")]
+    BadRequest { status: u16, body: String },
+    #[error("sdk-server error (HTTP {status}): {body}")]
+    ServerError { status: u16, body: String },
+    #[error("request failed: {0}")]
+    Transport(#[from] reqwest::Error),
+    #[error("failed to parse {what} response: {source}")]
+    InvalidResponse {
+        what: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("{} unresolved placeholder(s):\n  {}", references.len(), references.join("\n  "))]
+    Unresolved { references: Vec<String> },
+    #[error("offline and no cached copy of {what}")]
+    CacheMiss { what: String },
+    #[error("secrets changed since the lock file was written:\n  {}", changes.join("\n  "))]
+    LockMismatch { changes: Vec<String> },
+    #[error("template check found {problems} problem(s)")]
+    CheckFailed { problems: usize },
+    #[error("{reason}: {}", chain.join(" -> "))]
+    Reference { chain: Vec<String>, reason: String },
+    #[error("malformed access token: {reason}")]
+    InvalidToken { reason: String },
+    #[error("cannot reach agent at {path}: {source}")]
+    AgentUnavailable {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{message}")]
+    Agent {
+        kind: String,
+        message: String,
+        status: Option<u16>,
+        exit_code: u8,
+    },
+}
+
+impl BwsError {
+    /// Classify a non-success sdk-server response.
+    ///
+    /// The sdk-server reports most Bitwarden API failures as a 400 with the upstream
+    /// error in the body, so the body is inspected as well as the status.
+    pub fn from_response(status: StatusCode, body: String) -> Self {
+        let code = status.as_u16();
+        let lower = body.to_lowercase();
+        let unauthorized = [
+            "401 unauthorized",
+            "403 forbidden",
+            "invalid_client",
+            "invalid_grant",
+        ]
+        .iter()
+        .any(|m| lower.contains(m));
+        let not_found = lower.contains("not found");
+
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Self::Unauthorized { status: code, body }
+            }
+            StatusCode::NOT_FOUND => Self::NotFound { status: code, body },
+            _ if unauthorized => Self::Unauthorized { status: code, body },
+            _ if not_found => Self::NotFound { status: code, body },
+            _ if status.is_server_error() => Self::ServerError { status: code, body },
+            _ => Self::BadRequest { status: code, body },
+        }
+    }
+
+    /// Stable machine-readable name of the error kind
+    pub fn kind(&self) -> &str {
+        match self {
+            Self::Unauthorized { .. } => "unauthorized",
+            Self::NotFound { .. } => "not_found",
+            Self::KeyNotFound { .. } => "key_not_found",
+            Self::AmbiguousKey { .. } => "ambiguous_key",
+            Self::BadRequest { .. } => "bad_request",
+            Self::ServerError { .. } => "server_error",
+            Self::Transport(_) => "transport",
+            Self::InvalidResponse { .. } => "invalid_response",
+            Self::Unresolved { .. } => "unresolved",
+            Self::CacheMiss { .. } => "cache_miss",
+            Self::LockMismatch { .. } => "lock_mismatch",
+            Self::CheckFailed { .. } => "check_failed",
+            Self::Reference { .. } => "reference",
+            Self::InvalidToken { .. } => "invalid_token",
+            Self::AgentUnavailable { .. } => "agent_unavailable",
+            Self::Agent { kind, .. } => kind,
+        }
+    }
+
+    /// Process exit code for this error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Unauthorized { .. } => 3,
+            Self::NotFound { .. } | Self::KeyNotFound { .. } => 4,
+            Self::AmbiguousKey { .. } => 5,
+            Self::BadRequest { .. } => 6,
+            Self::ServerError { .. } => 7,
+            Self::Transport(_) => 8,
+            Self::InvalidResponse { .. } => 9,
+            Self::Unresolved { .. } => 10,
+            Self::CacheMiss { .. } => 11,
+            Self::LockMismatch { .. } => 12,
+            Self::CheckFailed { .. } => 13,
+            Self::Reference { .. } => 14,
+            Self::InvalidToken { .. } => 15,
+            Self::AgentUnavailable { .. } => 8,
+            Self::Agent { exit_code, .. } => *exit_code,
+        }
+    }
+
+    /// Whether the sdk-server could not be reached or failed, as opposed to rejecting the request
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Transport(_) | Self::ServerError { .. } | Self::AgentUnavailable { .. } => true,
+            Self::Agent { kind, .. } => kind == "transport" || kind == "server_error",
+            _ => false,
+        }
+    }
+
+    /// HTTP status of the failed response, if there was one
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Unauthorized { status, .. }
+            | Self::NotFound { status, .. }
+            | Self::BadRequest { status, .. }
+            | Self::ServerError { status, .. } => Some(*status),
+            Self::Transport(e) => e.status().map(|s| s.as_u16()),
+            Self::Agent { status, .. } => *status,
+            _ => None,
+        }
+    }
+
+    /// JSON representation used by `--error-format json`
+    pub fn to_json(&self) -> JsonValue {
+        serde_json::json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+            "status": self.status(),
+            "exit_code": self.exit_code(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_response_status() {
+        let err = BwsError::from_response(StatusCode::UNAUTHORIZED, String::new());
+        assert_eq!(err.kind(), "unauthorized");
+        let err = BwsError::from_response(StatusCode::INTERNAL_SERVER_ERROR, "boom".into());
+        assert_eq!(err.exit_code(), 7);
+    }
+
+    #[test]
+    fn test_from_response_body() {
+        let body = "failed to get secret: [404 Not Found] Resource not found.".to_string();
+        let err = BwsError::from_response(StatusCode::BAD_REQUEST, body);
+        assert_eq!(err.kind(), "not_found");
+
+        let body = r#"failed to login: {"error":"invalid_client"}"#.to_string();
+        let err = BwsError::from_response(StatusCode::BAD_REQUEST, body);
+        assert_eq!(err.exit_code(), 3);
+
+        let err = BwsError::from_response(
+            StatusCode::BAD_REQUEST,
+            "failed to unmarshal request".into(),
+        );
+        assert_eq!(err.kind(), "bad_request");
+    }
+}
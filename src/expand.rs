@@ -0,0 +1,288 @@
+use std::collections::BTreeSet;
+
+use crate::client::BwsClient;
+use crate::error::BwsError;
+use crate::models::SecretResponse;
+use crate::render::{Modifier, Placeholder, Resolution, Template, find_placeholders};
+
+/// How many levels `--recursive` follows references inside secret values by default
+pub const DEFAULT_MAX_DEPTH: usize = 8;
+
+/// A secret reference: by id, and the key or id
+type Ref = (bool, String);
+
+fn reference(ph: &Placeholder) -> Ref {
+    (ph.by_id, ph.key.clone())
+}
+
+fn name((by_id, key): &Ref) -> String {
+    match by_id {
+        true => format!("id:{}", key),
+        false => key.clone(),
+    }
+}
+
+fn fallback(ph: &Placeholder) -> bool {
+    matches!(ph.modifier, Modifier::Default(_) | Modifier::Optional)
+}
+
+fn missing(secret: Option<Result<&SecretResponse, &BwsError>>) -> bool {
+    matches!(
+        secret,
+        Some(Err(BwsError::KeyNotFound { .. } | BwsError::NotFound { .. }))
+    )
+}
+
+/// Why a value could not be expanded; `chain` runs from the secret being expanded to the problem
+#[derive(Clone)]
+struct Broken {
+    chain: Vec<String>,
+    reason: String,
+}
+
+impl Resolution {
+    /// Resolve `bws://` references inside the fetched values, following them up to `max_depth`
+    /// levels deep.
+    ///
+    /// Referenced secrets are fetched level by level, then every value is rendered after the values
+    /// it refers to. A secret whose references form a cycle, nest too deep or cannot be resolved
+    /// becomes a [`BwsError::Reference`] naming the whole chain.
+    pub async fn expand(
+        &mut self,
+        client: &BwsClient,
+        org_id: &str,
+        max_depth: usize,
+        verbose: bool,
+    ) -> Result<(), BwsError> {
+        for _ in 0..max_depth {
+            let mut keys = BTreeSet::new();
+            let mut ids = BTreeSet::new();
+            for ph in self.secrets().flat_map(|s| find_placeholders(&s.value)) {
+                match ph.by_id {
+                    true if self.get_id(&ph.key).is_none() => ids.insert(ph.key),
+                    false if self.get(&ph.key).is_none() => keys.insert(ph.key),
+                    _ => false,
+                };
+            }
+            if keys.is_empty() && ids.is_empty() {
+                break;
+            }
+            self.fetch(client, org_id, &keys, &ids).await?;
+            let fetched = keys.into_iter().map(|k| (false, k));
+            self.nested
+                .extend(fetched.chain(ids.into_iter().map(|id| (true, id))));
+        }
+
+        let roots: Vec<Ref> = self
+            .by_key
+            .keys()
+            .map(|k| (false, k.clone()))
+            .chain(self.by_id.keys().map(|k| (true, k.clone())))
+            .collect();
+        let outcomes: Vec<(Ref, Result<String, Broken>)> = roots
+            .into_iter()
+            .map(|root| {
+                let outcome = self.expand_one(&root, &mut Vec::new(), max_depth, verbose);
+                (root, outcome)
+            })
+            .collect();
+
+        for ((by_id, key), outcome) in outcomes {
+            let map = match by_id {
+                true => &mut self.by_id,
+                false => &mut self.by_key,
+            };
+            let Some(entry) = map.get_mut(&key) else {
+                continue;
+            };
+            match (outcome, entry.as_mut()) {
+                (Ok(value), Ok(secret)) => secret.value = value,
+                (Err(broken), Ok(_)) => {
+                    *entry = Err(BwsError::Reference {
+                        chain: broken.chain,
+                        reason: broken.reason,
+                    })
+                }
+                (_, Err(_)) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The fully expanded value of one secret; `stack` holds the secrets being expanded above it
+    fn expand_one(
+        &self,
+        r: &Ref,
+        stack: &mut Vec<Ref>,
+        max_depth: usize,
+        verbose: bool,
+    ) -> Result<String, Broken> {
+        let broken = |reason: String| Broken {
+            chain: vec![name(r)],
+            reason,
+        };
+        if stack.contains(r) {
+            return Err(broken("reference cycle".to_string()));
+        }
+        let secret = match r {
+            (true, id) => self.get_id(id),
+            (false, key) => self.get(key),
+        };
+        let secret = match secret {
+            Some(Ok(secret)) => secret,
+            Some(Err(e)) => return Err(broken(e.to_string())),
+            None => {
+                return Err(broken(format!(
+                    "nested more than {} level(s) deep",
+                    max_depth
+                )));
+            }
+        };
+        let template = Template::parse(name(r), secret.value.clone());
+        if template.is_static() {
+            return Ok(secret.value.clone());
+        }
+        if stack.len() >= max_depth {
+            return Err(broken(format!(
+                "nested more than {} level(s) deep",
+                max_depth
+            )));
+        }
+
+        stack.push(r.clone());
+        let mut local = Resolution::default();
+        let mut result = Ok(());
+        for ph in &template.placeholders {
+            // with --env, follow the environment secret the placeholder will use
+            let child = match self.lookup(ph) {
+                Some(Ok(secret)) if !ph.by_id => (false, secret.key.clone()),
+                _ => reference(ph),
+            };
+            match self.expand_one(&child, stack, max_depth, verbose) {
+                Ok(value) => {
+                    let Some(Ok(secret)) = self.lookup(ph) else {
+                        continue;
+                    };
+                    let secret = Ok(SecretResponse {
+                        value,
+                        ..secret.clone()
+                    });
+                    match child {
+                        (true, id) => local.by_id.insert(id, secret),
+                        (false, key) => {
+                            if key != ph.key {
+                                local.env_keys.insert(ph.key.clone(), key.clone());
+                            }
+                            local.by_key.insert(key, secret)
+                        }
+                    };
+                }
+                // a missing secret with ?default or ?optional falls back as it does at the top level
+                Err(_) if fallback(ph) && missing(self.for_placeholder(ph)) => {
+                    let key = ph.key.clone();
+                    let map = match ph.by_id {
+                        true => &mut local.by_id,
+                        false => &mut local.by_key,
+                    };
+                    map.insert(key.clone(), Err(BwsError::KeyNotFound { key }));
+                }
+                Err(mut inner) => {
+                    inner.chain.insert(0, name(r));
+                    result = Err(inner);
+                    break;
+                }
+            }
+        }
+        stack.pop();
+        result?;
+
+        let rendered = template.render(&local, verbose);
+        if let Some(unresolved) = rendered.unresolved().next() {
+            let mut chain = vec![name(r)];
+            if !unresolved.key.is_empty() {
+                chain.push(name(&(unresolved.by_id, unresolved.key.clone())));
+            }
+            return Err(Broken {
+                chain,
+                reason: unresolved.error.clone().unwrap_or_default(),
+            });
+        }
+        Ok(rendered.output)
+    }
+}
+
+/// Expand the references inside one fetched secret's value, for `get --recursive`
+pub async fn expand_secret(
+    client: &BwsClient,
+    org_id: &str,
+    secret: SecretResponse,
+    max_depth: usize,
+    verbose: bool,
+) -> Result<SecretResponse, BwsError> {
+    let mut resolution = Resolution::default();
+    let key = secret.key.clone();
+    resolution.by_key.insert(key.clone(), Ok(secret));
+    resolution
+        .expand(client, org_id, max_depth, verbose)
+        .await?;
+    resolution
+        .by_key
+        .remove(&key)
+        .expect("the expanded secret is kept")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(id: &str, key: &str, value: &str) -> Result<SecretResponse, BwsError> {
+        Ok(SecretResponse {
+            id: id.into(),
+            organization_id: "org".into(),
+            project_id: None,
+            key: key.into(),
+            value: value.into(),
+            note: String::new(),
+            creation_date: String::new(),
+            revision_date: String::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_expand_nested_and_cycles() {
+        let mut resolution = Resolution::default();
+        let secrets = [
+            ("id-h", "harbor", r#"{"pg_user":"harbor","pg_pass":"p@ss"}"#),
+            (
+                "id-d",
+                "dsn",
+                "postgres://bws://harbor/pg_user:bws://harbor/pg_pass@db",
+            ),
+            ("id-w", "wrapped", "dsn=bws://dsn bws://nope?default=x"),
+            ("id-a", "loop_a", "bws://loop_b"),
+            ("id-b", "loop_b", "x bws://loop_a"),
+        ];
+        for (id, key, value) in secrets {
+            resolution.by_key.insert(key.into(), secret(id, key, value));
+        }
+        let nope = || Err(BwsError::KeyNotFound { key: "nope".into() });
+        resolution.by_key.insert("nope".into(), nope());
+        // everything is already fetched, so the client is never used
+        let client = BwsClient::new(reqwest::Client::new(), Default::default(), "http://unused");
+        resolution.expand(&client, "org", 8, false).await.unwrap();
+
+        let value = |key: &str| resolution.get(key).unwrap().map(|s| s.value.clone());
+        assert_eq!(value("wrapped").unwrap(), "dsn=postgres://harbor:p@ss@db x");
+        let err = value("loop_a").unwrap_err().to_string();
+        assert_eq!(err, "reference cycle: loop_a -> loop_b -> loop_a");
+
+        let mut shallow = Resolution::default();
+        for (id, key, value) in secrets {
+            shallow.by_key.insert(key.into(), secret(id, key, value));
+        }
+        shallow.by_key.insert("nope".into(), nope());
+        shallow.expand(&client, "org", 1, false).await.unwrap();
+        let err = shallow.get("wrapped").unwrap().unwrap_err().to_string();
+        assert_eq!(err, "nested more than 1 level(s) deep: wrapped -> dsn");
+    }
+}
@@ -0,0 +1,127 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+use crate::mask::percent_encode;
+
+/// A transformation applied to a placeholder value, written `bws://key/path|filter|filter`.
+///
+/// Filters run left to right after the path is extracted (and after a `?default` is applied).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// `base64`, `b64enc`: standard base64 encoding, e.g. for Kubernetes `Secret.data`
+    Base64,
+    /// `base64-decode`, `b64dec`: decode standard base64; the result must be UTF-8
+    Base64Decode,
+    /// `urlencode`: percent-encode everything except RFC 3986 unreserved characters
+    UrlEncode,
+    /// `json-escape`: escape for use inside a JSON string, without the surrounding quotes
+    JsonEscape,
+    /// `trim`: strip leading and trailing whitespace
+    Trim,
+    /// `sha256`: lowercase hex SHA-256 digest, e.g. for config checksums
+    Sha256,
+}
+
+/// Every filter name, for error messages and documentation
+pub const FILTER_NAMES: &[&str] = &[
+    "base64",
+    "b64enc",
+    "base64-decode",
+    "b64dec",
+    "urlencode",
+    "json-escape",
+    "trim",
+    "sha256",
+];
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "base64" | "b64enc" => Ok(Self::Base64),
+            "base64-decode" | "b64dec" => Ok(Self::Base64Decode),
+            "urlencode" => Ok(Self::UrlEncode),
+            "json-escape" => Ok(Self::JsonEscape),
+            "trim" => Ok(Self::Trim),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(format!(
+                "unknown filter '{}', expected one of: {}",
+                name,
+                FILTER_NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl Filter {
+    pub fn apply(self, value: &str) -> Result<String, String> {
+        match self {
+            Self::Base64 => Ok(BASE64.encode(value)),
+            Self::Base64Decode => {
+                let bytes = BASE64
+                    .decode(value.trim())
+                    .map_err(|e| format!("base64-decode: {}", e))?;
+                String::from_utf8(bytes)
+                    .map_err(|_| "base64-decode: decoded value is not UTF-8".to_string())
+            }
+            Self::UrlEncode => Ok(percent_encode(value)),
+            Self::JsonEscape => {
+                let quoted = JsonValue::from(value).to_string();
+                Ok(quoted[1..quoted.len() - 1].to_string())
+            }
+            Self::Trim => Ok(value.trim().to_string()),
+            Self::Sha256 => Ok(Sha256::digest(value.as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect()),
+        }
+    }
+}
+
+/// Parse a `|a|b` filter chain
+pub fn parse_chain(chain: &str) -> Result<Vec<Filter>, String> {
+    chain
+        .split('|')
+        .filter(|name| !name.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Run a value through a filter chain
+pub fn apply_chain(filters: &[Filter], value: String) -> Result<String, String> {
+    filters
+        .iter()
+        .try_fold(value, |value, filter| filter.apply(&value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_chain() {
+        let filters = parse_chain("|trim|b64enc").unwrap();
+        assert_eq!(
+            apply_chain(&filters, " p@ss \n".into()).unwrap(),
+            "cEBzcw=="
+        );
+        let filters = parse_chain("|b64dec|urlencode").unwrap();
+        assert_eq!(apply_chain(&filters, "cEBzcw==".into()).unwrap(), "p%40ss");
+        assert_eq!(
+            Filter::Sha256.apply("abc").unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(Filter::JsonEscape.apply("a\"b\n").unwrap(), r#"a\"b\n"#);
+    }
+
+    #[test]
+    fn test_unknown_filter() {
+        let err = parse_chain("|trim|upper").unwrap_err();
+        assert!(err.starts_with("unknown filter 'upper'"));
+        assert!(Filter::Base64Decode.apply("not base64!").is_err());
+    }
+}
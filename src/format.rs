@@ -0,0 +1,482 @@
+use regex::Regex;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::render::Placeholder;
+
+/// A YAML line ending in a block scalar indicator (`key: |`, `- >-`, `key: |2`)
+static BLOCK_INDICATOR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|\s)[|>][-+]?[0-9]?[-+]?$").unwrap());
+
+/// TOML values that are valid unquoted: booleans, integers and floats
+static TOML_BARE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:true|false|[+-]?(?:0|[1-9][0-9_]*)(?:\.[0-9_]+)?(?:[eE][+-]?[0-9]+)?)$")
+        .unwrap()
+});
+
+/// Plain YAML scalars that YAML 1.1 or 1.2 resolve to something other than a string: null,
+/// booleans (including `yes`/`no`/`on`/`off`), numbers in any base, `.inf`/`.nan`, sexagesimal
+/// numbers, timestamps, and the `<<` merge and `=` value keys
+static YAML_NON_STRING_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?x)^(?:
+            ~ | null | Null | NULL
+            | y | Y | yes | Yes | YES | n | N | no | No | NO
+            | true | True | TRUE | false | False | FALSE
+            | on | On | ON | off | Off | OFF
+            | << | =
+            | [-+]?\.(?:inf|Inf|INF) | \.(?:nan|NaN|NAN)
+            | [-+]?\.?[0-9][0-9a-zA-Z_.:+-]*
+            | [0-9]{4}-[0-9]{1,2}-[0-9]{1,2}[Tt\ ].*
+        )$",
+    )
+    .unwrap()
+});
+
+/// Syntax of a template, deciding how substituted values are quoted and escaped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Paste values as-is (multiline values alone on a line are indented to match)
+    #[default]
+    Raw,
+    Yaml,
+    Json,
+    Toml,
+    Dotenv,
+}
+
+impl Format {
+    /// Guess the format from a file name: `.yaml`/`.yml`, `.json`, `.toml`, `.env`/`*.env`,
+    /// anything else is raw
+    pub fn from_path(path: &Path) -> Self {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match path.extension().and_then(|e| e.to_str()) {
+            _ if name == ".env" || name.starts_with(".env.") => Self::Dotenv,
+            Some(ext) => match ext.to_lowercase().as_str() {
+                "yaml" | "yml" => Self::Yaml,
+                "json" => Self::Json,
+                "toml" => Self::Toml,
+                "env" => Self::Dotenv,
+                _ => Self::Raw,
+            },
+            None => Self::Raw,
+        }
+    }
+
+    /// Quote and escape `value` for where it is placed.
+    ///
+    /// Errors describe why the value cannot be represented there, e.g. a newline inside a
+    /// single-quoted string.
+    pub(crate) fn encode(self, slot: Slot, value: &str) -> Result<String, String> {
+        match (self, slot) {
+            (_, Slot::Verbatim | Slot::Comment) => Ok(value.to_string()),
+            (Self::Dotenv, Slot::Double) => Ok(dotenv_escape(value)),
+            (_, Slot::Double) => Ok(json_escape(value)),
+            (Self::Yaml, Slot::Single) if value.contains('\n') => Err(
+                "multiline value inside a single-quoted YAML string; use double quotes".to_string(),
+            ),
+            (Self::Yaml, Slot::Single) => Ok(value.replace('\'', "''")),
+            (_, Slot::Single) if value.contains(['\'', '\n']) => Err(format!(
+                "value contains a quote or newline, which a single-quoted {} string cannot hold; \
+                 use double quotes",
+                self.name()
+            )),
+            (_, Slot::Single) => Ok(value.to_string()),
+            (_, Slot::Triple { literal: true }) if value.contains("'''") => {
+                Err("value contains ''', which a TOML literal string cannot hold".to_string())
+            }
+            (_, Slot::Triple { literal: true }) => Ok(value.to_string()),
+            (_, Slot::Triple { literal: false }) => {
+                Ok(value.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            // valid JSON is kept as written: parsing and printing it again would change numbers
+            // such as 12345678901234567890123 or 1e5
+            (Self::Json, Slot::Bare { .. }) => {
+                Ok(match serde_json::from_str::<IgnoredAny>(value) {
+                    Ok(_) => value.to_string(),
+                    Err(_) => JsonValue::from(value).to_string(),
+                })
+            }
+            (Self::Toml, Slot::Bare { .. }) if TOML_BARE_RE.is_match(value) => {
+                Ok(value.to_string())
+            }
+            (Self::Yaml, Slot::Bare { .. }) if yaml_plain_safe(value) => Ok(value.to_string()),
+            (Self::Dotenv, Slot::Bare { .. }) if dotenv_bare_safe(value) => Ok(value.to_string()),
+            // single quotes keep `$` from being expanded; they cannot hold a quote or escape
+            (Self::Dotenv, Slot::Bare { .. })
+                if value.contains('$') && !value.contains(['\'', '\\', '\n']) =>
+            {
+                Ok(format!("'{}'", value))
+            }
+            (Self::Dotenv, Slot::Bare { .. }) => Ok(format!("\"{}\"", dotenv_escape(value))),
+            (_, Slot::Bare { .. }) => Ok(JsonValue::from(value).to_string()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Yaml => "YAML",
+            Self::Json => "JSON",
+            Self::Toml => "TOML",
+            Self::Dotenv => "dotenv",
+        }
+    }
+}
+
+/// Where a placeholder sits in the surrounding syntax
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Slot {
+    /// Inserted as-is: raw templates and YAML block scalars
+    Verbatim,
+    /// Inside a `"..."` string
+    Double,
+    /// Inside a `'...'` string
+    Single,
+    /// Inside a TOML `"""..."""` or `'''...'''` string
+    Triple { literal: bool },
+    /// Inside a comment; left untouched
+    Comment,
+    /// An unquoted value spanning `start..end` of the template, which may hold further
+    /// placeholders and literal text; the whole span is quoted if needed
+    Bare { start: usize, end: usize },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scan {
+    Normal,
+    Double,
+    Single,
+    Triple { literal: bool },
+    Comment,
+    Block { parent_indent: usize },
+}
+
+/// Find the slot of every placeholder with one pass over the template.
+///
+/// This is a lexer, not a parser: it tracks strings, comments and (for YAML) block scalars so
+/// it copes with multi-document files and anything else the format allows between values.
+pub(crate) fn slots(format: Format, text: &str, placeholders: &[Placeholder]) -> Vec<Slot> {
+    if format == Format::Raw {
+        return vec![Slot::Verbatim; placeholders.len()];
+    }
+    let bytes = text.as_bytes();
+    let mut slots = Vec::with_capacity(placeholders.len());
+    let mut next = placeholders.iter().peekable();
+    let mut state = Scan::Normal;
+    let mut line_start = 0;
+    let mut i = 0;
+
+    loop {
+        while let Some(ph) = next.next_if(|ph| ph.start <= i) {
+            slots.push(match state {
+                Scan::Normal => bare_slot(format, text, line_start, ph),
+                Scan::Double => Slot::Double,
+                Scan::Single => Slot::Single,
+                Scan::Triple { literal } => Slot::Triple { literal },
+                Scan::Comment => Slot::Comment,
+                Scan::Block { .. } => Slot::Verbatim,
+            });
+            // the placeholder's own quotes (`${bws:"key"}`) are not template syntax
+            i = i.max(ph.end);
+        }
+        if i >= bytes.len() {
+            break;
+        }
+        let b = bytes[i];
+        let prev = i.checked_sub(1).map(|p| bytes[p]);
+        let rest = &text[i..];
+        let mut step = 1;
+
+        if b == b'\n' {
+            line_start = i + 1;
+            state = match state {
+                Scan::Normal | Scan::Comment
+                    if format == Format::Yaml
+                        && BLOCK_INDICATOR_RE.is_match(value_part(&text[line_of(text, i)..i])) =>
+                {
+                    Scan::Block {
+                        parent_indent: indent(&text[line_of(text, i)..i]),
+                    }
+                }
+                Scan::Comment => Scan::Normal,
+                // only YAML flow scalars and TOML multi-line strings continue over lines
+                Scan::Double | Scan::Single if format != Format::Yaml => Scan::Normal,
+                other => other,
+            };
+            if let Scan::Block { parent_indent } = state {
+                let line = text[line_start..].split('\n').next().unwrap_or_default();
+                if !line.trim().is_empty() && indent(line) <= parent_indent {
+                    state = Scan::Normal;
+                }
+            }
+        } else {
+            match state {
+                Scan::Normal => match b {
+                    b'#' if format != Format::Json
+                        && prev.is_none_or(|p| p.is_ascii_whitespace()) =>
+                    {
+                        state = Scan::Comment
+                    }
+                    b'"' | b'\'' if opens_string(format, prev) => {
+                        let literal = b == b'\'';
+                        let triple = if literal { "'''" } else { "\"\"\"" };
+                        if format == Format::Toml && rest.starts_with(triple) {
+                            state = Scan::Triple { literal };
+                            step = 3;
+                        } else if literal {
+                            state = Scan::Single;
+                        } else {
+                            state = Scan::Double;
+                        }
+                    }
+                    _ => {}
+                },
+                Scan::Double => match b {
+                    b'\\' => step = 2,
+                    b'"' => state = Scan::Normal,
+                    _ => {}
+                },
+                Scan::Single => {
+                    if b == b'\'' {
+                        if format == Format::Yaml && rest.starts_with("''") {
+                            step = 2;
+                        } else {
+                            state = Scan::Normal;
+                        }
+                    }
+                }
+                Scan::Triple { literal } => {
+                    if !literal && b == b'\\' {
+                        step = 2;
+                    } else if rest.starts_with(if literal { "'''" } else { "\"\"\"" }) {
+                        state = Scan::Normal;
+                        step = 3;
+                    }
+                }
+                Scan::Comment | Scan::Block { .. } => {}
+            }
+        }
+        i = (i + step).min(bytes.len());
+        while !text.is_char_boundary(i) {
+            i += 1;
+        }
+    }
+    slots
+}
+
+/// Whether a quote at this position starts a string rather than being part of a bare value
+/// (as in `it's`)
+fn opens_string(format: Format, prev: Option<u8>) -> bool {
+    format == Format::Json || prev.is_none_or(|p| p.is_ascii_whitespace() || b"=:[{,".contains(&p))
+}
+
+/// The unquoted value a placeholder belongs to. YAML and dotenv values run to the end of the
+/// line (or a comment); JSON and TOML values are single tokens.
+fn bare_slot(format: Format, text: &str, line_start: usize, ph: &Placeholder) -> Slot {
+    let own = Slot::Bare {
+        start: ph.start,
+        end: ph.end,
+    };
+    let line_end = text[ph.end..].find('\n').map_or(text.len(), |i| ph.end + i);
+    let before = &text[line_start..ph.start];
+    let start = match format {
+        Format::Yaml => {
+            if let Some(i) = before.rfind(": ") {
+                line_start + i + 2
+            } else if let Some(item) = before.trim_start().strip_prefix("- ") {
+                ph.start - item.len()
+            } else {
+                line_start + indent(before)
+            }
+        }
+        Format::Dotenv => before.find('=').map_or(line_start, |i| line_start + i + 1),
+        _ => return own,
+    };
+    let start = start + (text[start..ph.start].len() - text[start..ph.start].trim_start().len());
+    if text[start..].starts_with(['[', '{']) {
+        return own;
+    }
+    let end = text[ph.end..line_end]
+        .find(" #")
+        .map_or(line_end, |i| ph.end + i);
+    let end = start + text[start..end].trim_end().len();
+    Slot::Bare { start, end }
+}
+
+/// Start of the line containing byte `i`
+fn line_of(text: &str, i: usize) -> usize {
+    text[..i].rfind('\n').map_or(0, |p| p + 1)
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// A line without its trailing comment
+fn value_part(line: &str) -> &str {
+    let end = line.find(" #").unwrap_or(line.len());
+    line[..end].trim_end()
+}
+
+/// Contents of a JSON string literal; also valid in YAML and TOML double-quoted strings
+fn json_escape(value: &str) -> String {
+    let quoted = JsonValue::from(value).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Contents of a double-quoted dotenv value; `$` is escaped since docker compose, shells and
+/// python-dotenv expand variables inside double quotes
+fn dotenv_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$")
+        .replace('\n', "\\n")
+}
+
+/// Whether a YAML plain scalar can hold `value` unchanged
+fn yaml_plain_safe(value: &str) -> bool {
+    !value.is_empty()
+        && value.trim() == value
+        && !value.starts_with([
+            '-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%',
+            '@', '`',
+        ])
+        && !value.ends_with(':')
+        && !value.contains(": ")
+        && !value.contains(" #")
+        && !value.contains(['\n', '\t'])
+        && !YAML_NON_STRING_RE.is_match(value)
+}
+
+fn dotenv_bare_safe(value: &str) -> bool {
+    !value.is_empty()
+        && !value.contains(|c: char| {
+            c.is_whitespace() || matches!(c, '"' | '\'' | '#' | '\\' | '$' | '`')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::find_placeholders;
+
+    fn slots_of(format: Format, text: &str) -> Vec<Slot> {
+        slots(format, text, &find_placeholders(text))
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path(Path::new("a/values.yml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("config.JSON")), Format::Json);
+        assert_eq!(
+            Format::from_path(Path::new(".env.production")),
+            Format::Dotenv
+        );
+        assert_eq!(Format::from_path(Path::new("app.env")), Format::Dotenv);
+        assert_eq!(Format::from_path(Path::new("nginx.conf")), Format::Raw);
+    }
+
+    #[test]
+    fn test_yaml_slots() {
+        let text = "# bws://a\n---\nx: \"bws://a\"\ny: 'bws://a'\nz: pre-bws://a # c\nblock: |\n  bws://a\nnext: bws://a\n";
+        let slots = slots_of(Format::Yaml, text);
+        assert_eq!(slots[0], Slot::Comment);
+        assert_eq!(slots[1], Slot::Double);
+        assert_eq!(slots[2], Slot::Single);
+        let Slot::Bare { start, end } = slots[3] else {
+            panic!("expected a bare slot");
+        };
+        assert_eq!(&text[start..end], "pre-bws://a");
+        assert_eq!(slots[4], Slot::Verbatim);
+        assert!(matches!(slots[5], Slot::Bare { .. }));
+    }
+
+    #[test]
+    fn test_encode_escapes() {
+        let pass = r#"p@ss:w#rd"x"#;
+        assert_eq!(
+            Format::Yaml.encode(Slot::Bare { start: 0, end: 0 }, "a: b #c"),
+            Ok(r#""a: b #c""#.to_string())
+        );
+        assert_eq!(
+            Format::Yaml.encode(Slot::Bare { start: 0, end: 0 }, "plain"),
+            Ok("plain".to_string())
+        );
+        assert_eq!(
+            Format::Json.encode(Slot::Double, pass),
+            Ok(r#"p@ss:w#rd\"x"#.to_string())
+        );
+        assert_eq!(
+            Format::Toml.encode(Slot::Bare { start: 0, end: 0 }, "5432"),
+            Ok("5432".to_string())
+        );
+        assert_eq!(
+            Format::Dotenv.encode(Slot::Bare { start: 0, end: 0 }, "a b"),
+            Ok(r#""a b""#.to_string())
+        );
+        assert!(Format::Yaml.encode(Slot::Single, "a\nb").is_err());
+
+        let bare = Slot::Bare { start: 0, end: 0 };
+        assert_eq!(
+            Format::Dotenv.encode(bare, "pa$word"),
+            Ok("'pa$word'".to_string())
+        );
+        assert_eq!(
+            Format::Dotenv.encode(bare, "it's $5"),
+            Ok(r#""it's \$5""#.to_string())
+        );
+        assert_eq!(
+            Format::Dotenv.encode(Slot::Double, "pa$word"),
+            Ok(r"pa\$word".to_string())
+        );
+    }
+
+    #[test]
+    fn test_encode_keeps_types() {
+        let bare = Slot::Bare { start: 0, end: 0 };
+        // JSON numbers are copied, not parsed and printed again
+        for value in ["12345678901234567890123", "1e5", "0.10", r#"{"a": 1.0}"#] {
+            assert_eq!(Format::Json.encode(bare, value), Ok(value.to_string()));
+        }
+        assert_eq!(
+            Format::Json.encode(bare, "not json"),
+            Ok(r#""not json""#.to_string())
+        );
+        // YAML scalars that would not stay strings are quoted
+        for value in [
+            "yes",
+            "No",
+            "on",
+            "OFF",
+            "null",
+            "~",
+            "true",
+            "0777",
+            "1e3",
+            "0x1F",
+            ".inf",
+            "12:30",
+            "2024-01-02",
+            "5432",
+        ] {
+            assert_eq!(
+                Format::Yaml.encode(bare, value),
+                Ok(format!("\"{}\"", value)),
+                "{}",
+                value
+            );
+        }
+        for value in ["yesterday", "nothing", "v1.2", "harbor"] {
+            assert_eq!(Format::Yaml.encode(bare, value), Ok(value.to_string()));
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use minijinja::value::{Kwargs, Value};
+use minijinja::{AutoEscape, Environment, Error, ErrorKind, UndefinedBehavior};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+
+use crate::client::BwsClient;
+use crate::error::BwsError;
+use crate::models::{SecretIdentifierResponse, SecretResponse};
+use crate::render::{BATCH_SIZE, extract_path, secret_replacement};
+
+/// A secret as seen by `secrets()` in a template
+#[derive(Serialize)]
+struct SecretItem {
+    id: String,
+    key: String,
+    value: String,
+    note: String,
+    /// `value` parsed as JSON, or the string itself
+    parsed: serde_json::Value,
+}
+
+/// Secrets looked up on demand while a template renders. The list is fetched at most once and
+/// every secret at most once, however often the template refers to it.
+struct Lookup {
+    client: BwsClient,
+    org_id: String,
+    handle: Handle,
+    verbose: bool,
+    list: Mutex<Option<Vec<SecretIdentifierResponse>>>,
+    fetched: Mutex<HashMap<String, SecretResponse>>,
+}
+
+impl Lookup {
+    fn list(&self) -> Result<Vec<SecretIdentifierResponse>, BwsError> {
+        let mut list = self.list.lock().unwrap();
+        if list.is_none() {
+            *list = Some(self.handle.block_on(self.client.list(&self.org_id))?.data);
+        }
+        Ok(list.clone().unwrap_or_default())
+    }
+
+    /// Fetch secrets by id, only asking the sdk-server for ones not seen yet
+    fn fetch(&self, ids: &[String]) -> Result<Vec<SecretResponse>, BwsError> {
+        let mut fetched = self.fetched.lock().unwrap();
+        let missing: Vec<String> = ids
+            .iter()
+            .filter(|id| !fetched.contains_key(*id))
+            .cloned()
+            .collect();
+        for chunk in missing.chunks(BATCH_SIZE) {
+            let secrets = self
+                .handle
+                .block_on(self.client.get_by_ids(chunk.to_vec()))?;
+            for secret in secrets.data {
+                fetched.insert(secret.id.clone(), secret);
+            }
+        }
+        Ok(ids
+            .iter()
+            .filter_map(|id| fetched.get(id).cloned())
+            .collect())
+    }
+
+    fn by_key(&self, key: &str) -> Result<SecretResponse, BwsError> {
+        let ids: Vec<String> = self
+            .list()?
+            .into_iter()
+            .filter(|i| i.key == key)
+            .map(|i| i.id)
+            .collect();
+        let not_found = || BwsError::KeyNotFound {
+            key: key.to_string(),
+        };
+        match ids.len() {
+            0 => Err(not_found()),
+            1 => self.fetch(&ids)?.pop().ok_or_else(not_found),
+            _ => Err(BwsError::AmbiguousKey {
+                key: key.to_string(),
+                ids,
+            }),
+        }
+    }
+
+    fn with_prefix(&self, prefix: &str) -> Result<Vec<SecretItem>, BwsError> {
+        let ids: Vec<String> = self
+            .list()?
+            .into_iter()
+            .filter(|i| i.key.starts_with(prefix))
+            .map(|i| i.id)
+            .collect();
+        let mut items: Vec<SecretItem> = self
+            .fetch(&ids)?
+            .into_iter()
+            .map(|s| SecretItem {
+                parsed: s.parsed_value(),
+                id: s.id,
+                key: s.key,
+                value: s.value,
+                note: s.note,
+            })
+            .collect();
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(items)
+    }
+}
+
+fn template_error(err: BwsError) -> Error {
+    Error::new(ErrorKind::InvalidOperation, err.to_string()).with_source(err)
+}
+
+/// Build the environment exposing `secret`, `secret_field`, `secrets` and `env`
+fn environment(lookup: Arc<Lookup>) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_keep_trailing_newline(true);
+    // values go in as-is, whatever the template's file extension
+    env.set_auto_escape_callback(|_| AutoEscape::None);
+
+    let l = lookup.clone();
+    env.add_function("secret", move |key: String| -> Result<String, Error> {
+        let secret = l.by_key(&key).map_err(template_error)?;
+        Ok(secret_replacement(&secret, &key, None, l.verbose).unwrap_or_default())
+    });
+
+    let l = lookup.clone();
+    env.add_function(
+        "secret_field",
+        move |key: String, path: String| -> Result<Value, Error> {
+            let secret = l.by_key(&key).map_err(template_error)?;
+            extract_path(&secret.parsed_value(), &path)
+                .map(Value::from_serialize)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidOperation,
+                        format!("path '{}' not found in secret '{}'", path, key),
+                    )
+                })
+        },
+    );
+
+    let l = lookup;
+    env.add_function(
+        "secrets",
+        move |prefix: Option<String>, kwargs: Kwargs| -> Result<Value, Error> {
+            let prefix = match prefix {
+                Some(prefix) => prefix,
+                None => kwargs.get::<Option<String>>("prefix")?.unwrap_or_default(),
+            };
+            kwargs.assert_all_used()?;
+            let items = l.with_prefix(&prefix).map_err(template_error)?;
+            Ok(Value::from_serialize(&items))
+        },
+    );
+
+    env.add_function(
+        "env",
+        |name: String, default: Option<String>| -> Result<String, Error> {
+            std::env::var(&name).or_else(|_| {
+                default.ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidOperation,
+                        format!("environment variable {} is not set", name),
+                    )
+                })
+            })
+        },
+    );
+    env
+}
+
+/// Render a MiniJinja template, fetching secrets lazily as the template asks for them.
+///
+/// Rendering runs on a blocking thread so template functions can wait on the client.
+pub async fn render_jinja(
+    client: &BwsClient,
+    org_id: &str,
+    source: String,
+    text: String,
+    verbose: bool,
+) -> anyhow::Result<String> {
+    let lookup = Arc::new(Lookup {
+        client: client.clone(),
+        org_id: org_id.to_string(),
+        handle: Handle::current(),
+        verbose,
+        list: Mutex::new(None),
+        fetched: Mutex::new(HashMap::new()),
+    });
+    let output = tokio::task::spawn_blocking(move || {
+        environment(lookup).render_named_str(&source, &text, ())
+    })
+    .await??;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[tokio::test]
+    async fn test_render_without_secrets() {
+        let client = BwsClient::new(reqwest::Client::new(), Default::default(), "http://unused");
+        let text = "{% for n in [1, 2] %}{% if n > 1 %}{{ env(\"BWS_JINJA_TEST_UNSET\", \"d\") }}{% endif %}{% endfor %}\n";
+        let out = render_jinja(&client, "org", "t.j2".into(), text.into(), false)
+            .await
+            .unwrap();
+        assert_eq!(out, "d\n");
+
+        let err = render_jinja(&client, "org", "t.j2".into(), "{{ nope }}".into(), false)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("t.j2"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_render_secrets_from_server() {
+        let port = mock::with_secrets(&[
+            ("id-h", "harbor", r#"{"user":"admin","pass":"p\"w<&>"}"#),
+            ("id-p", "db_pass", "a\"b<c>&'d"),
+            ("id-u", "db_user", "app"),
+            ("id-x", "other", "x"),
+        ])
+        .await;
+        let client = mock::client(port);
+        let render =
+            |name: &str, text: &str| render_jinja(&client, "org", name.into(), text.into(), false);
+
+        // no escaping for names minijinja would escape by default
+        for name in ["values.yaml.j2", "config.json", "page.html", "t.j2"] {
+            let out = render(name, "pass: {{ secret(\"db_pass\") }}\n")
+                .await
+                .unwrap();
+            assert_eq!(out, "pass: a\"b<c>&'d\n", "{}", name);
+        }
+        let out = render("t.yaml", "{{ secret_field(\"harbor\", \"pass\") }}")
+            .await
+            .unwrap();
+        assert_eq!(out, "p\"w<&>");
+        let out = render(
+            "t.json",
+            "{% for s in secrets(prefix=\"db_\") %}{{ s.key }}={{ s.value }};{% endfor %}",
+        )
+        .await
+        .unwrap();
+        assert_eq!(out, "db_pass=a\"b<c>&'d;db_user=app;");
+
+        let err = render("t.j2", "{{ secret(\"missing\") }}")
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("secret with key 'missing' not found"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,30 @@
+//! Client library for the external-secrets bitwarden-sdk-server.
+//!
+//! The `bws-connector` binary is a thin CLI over this crate; other Rust tools can
+//! use [`BwsClient`] directly to read and manage secrets.
+
+pub mod agent;
+pub mod batch;
+pub mod cache;
+pub mod check;
+pub mod client;
+pub mod config;
+pub mod doctor;
+pub mod error;
+pub mod expand;
+pub mod filter;
+pub mod format;
+pub mod jinja;
+pub mod lock;
+pub mod mask;
+#[cfg(test)]
+mod mock;
+pub mod models;
+pub mod overlay;
+pub mod render;
+pub mod run;
+pub mod tls;
+pub mod token;
+
+pub use client::{BwsClient, ClientSettings};
+pub use error::BwsError;
@@ -0,0 +1,207 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::error::BwsError;
+use crate::render::{NestedSecret, PlaceholderReport};
+
+/// Lock file format version written by this build
+const LOCK_VERSION: u32 = 1;
+
+/// The secrets a render used, written by `render --lock` and checked by `render --frozen`.
+///
+/// Records the id and `revisionDate` of every referenced secret, including secrets referenced from
+/// inside other secrets with `--recursive`, never a value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub version: u32,
+    /// Keyed by reference: the secret key, or `id:<id>` for the ID form. `null` records a
+    /// reference that did not match any secret.
+    pub secrets: BTreeMap<String, Option<LockedSecret>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedSecret {
+    pub id: String,
+    pub revision_date: String,
+}
+
+impl Lockfile {
+    /// Build a lock from the placeholder reports of one or more rendered templates
+    pub fn from_placeholders<'a>(
+        placeholders: impl IntoIterator<Item = &'a PlaceholderReport>,
+    ) -> Self {
+        let mut secrets = BTreeMap::new();
+        for ph in placeholders {
+            let reference = match ph.by_id {
+                true => format!("id:{}", ph.key),
+                false => ph.key.clone(),
+            };
+            let locked = ph
+                .secret_id
+                .clone()
+                .zip(ph.revision_date.clone())
+                .map(|(id, revision_date)| LockedSecret { id, revision_date });
+            let entry = secrets.entry(reference).or_insert(None);
+            if entry.is_none() {
+                *entry = locked;
+            }
+        }
+        Self {
+            version: LOCK_VERSION,
+            secrets,
+        }
+    }
+
+    /// Also lock the secrets `--recursive` followed from inside secret values
+    pub fn with_nested<'a>(mut self, nested: impl IntoIterator<Item = &'a NestedSecret>) -> Self {
+        for secret in nested {
+            let locked = secret
+                .secret_id
+                .clone()
+                .zip(secret.revision_date.clone())
+                .map(|(id, revision_date)| LockedSecret { id, revision_date });
+            let entry = self.secrets.entry(secret.reference.clone()).or_insert(None);
+            if entry.is_none() {
+                *entry = locked;
+            }
+        }
+        self
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading lock file {}", path.display()))?;
+        let lock: Self = serde_json::from_str(&data)
+            .with_context(|| format!("parsing lock file {}", path.display()))?;
+        anyhow::ensure!(
+            lock.version == LOCK_VERSION,
+            "lock file {} has unsupported version {}",
+            path.display(),
+            lock.version
+        );
+        Ok(lock)
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        std::fs::write(path, json).with_context(|| format!("writing lock file {}", path.display()))
+    }
+
+    /// Fail with every reference in `current` that differs from this lock: a new revision, a
+    /// different secret, a secret created or deleted, or a reference the lock does not cover
+    pub fn verify(&self, current: &Lockfile) -> Result<(), BwsError> {
+        let mut changes = Vec::new();
+        for (reference, now) in &current.secrets {
+            let Some(locked) = self.secrets.get(reference) else {
+                changes.push(format!("{}: not in the lock file", reference));
+                continue;
+            };
+            match (locked, now) {
+                (Some(locked), Some(now)) if locked.id != now.id => changes.push(format!(
+                    "{}: locked to secret {}, now secret {}",
+                    reference, locked.id, now.id
+                )),
+                (Some(locked), Some(now)) if locked.revision_date != now.revision_date => changes
+                    .push(format!(
+                        "{}: revision {} changed to {}",
+                        reference, locked.revision_date, now.revision_date
+                    )),
+                (Some(locked), None) => changes.push(format!(
+                    "{}: locked secret {} no longer exists",
+                    reference, locked.id
+                )),
+                (None, Some(now)) => changes.push(format!(
+                    "{}: secret {} was created after locking",
+                    reference, now.id
+                )),
+                _ => {}
+            }
+        }
+        if changes.is_empty() {
+            Ok(())
+        } else {
+            Err(BwsError::LockMismatch { changes })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::render::{ResolveOptions, Template, render_template};
+
+    fn lock(entries: &[(&str, Option<(&str, &str)>)]) -> Lockfile {
+        Lockfile {
+            version: LOCK_VERSION,
+            secrets: entries
+                .iter()
+                .map(|(reference, locked)| {
+                    let locked = locked.map(|(id, revision_date)| LockedSecret {
+                        id: id.into(),
+                        revision_date: revision_date.into(),
+                    });
+                    (reference.to_string(), locked)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_verify_reports_every_change() {
+        let locked = lock(&[
+            ("harbor", Some(("id-1", "2024-01-01T00:00:00Z"))),
+            ("dsn", Some(("id-2", "2024-01-01T00:00:00Z"))),
+            ("gone", Some(("id-3", "2024-01-01T00:00:00Z"))),
+            ("later", None),
+        ]);
+        assert!(locked.verify(&locked).is_ok());
+
+        let current = lock(&[
+            ("harbor", Some(("id-1", "2024-01-01T00:00:00Z"))),
+            ("dsn", Some(("id-2", "2024-02-01T00:00:00Z"))),
+            ("gone", None),
+            ("later", Some(("id-4", "2024-02-01T00:00:00Z"))),
+            ("new", None),
+        ]);
+        let Err(BwsError::LockMismatch { changes }) = locked.verify(&current) else {
+            panic!("expected a lock mismatch");
+        };
+        assert_eq!(
+            changes,
+            [
+                "dsn: revision 2024-01-01T00:00:00Z changed to 2024-02-01T00:00:00Z",
+                "gone: locked secret id-3 no longer exists",
+                "later: secret id-4 was created after locking",
+                "new: not in the lock file",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lock_records_nested_secrets() {
+        let port = mock::with_secrets(&[
+            ("id-d", "dsn", "postgres://app:bws://db_pass@db"),
+            ("id-p", "db_pass", "s3cret"),
+        ])
+        .await;
+        let template = Template::parse("t", "url: bws://dsn\n".to_string());
+        let options = ResolveOptions {
+            recursive: Some(8),
+            ..Default::default()
+        };
+        let rendered = render_template(&template, &mock::client(port), "org", &options, false)
+            .await
+            .unwrap();
+        assert_eq!(rendered.output, "url: postgres://app:s3cret@db\n");
+
+        let lock =
+            Lockfile::from_placeholders(&rendered.placeholders).with_nested(&rendered.nested);
+        assert_eq!(lock.secrets.keys().collect::<Vec<_>>(), ["db_pass", "dsn"]);
+        assert_eq!(lock.secrets["db_pass"].as_ref().unwrap().id, "id-p");
+    }
+}
@@ -1,163 +1,551 @@
 mod cli;
-mod client;
-mod models;
 mod output;
-mod render;
 
 use anyhow::Context;
 use clap::Parser;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
 
-use cli::{Cli, Commands};
-use models::*;
+use bws_connector::agent::{self, AGENT_SOCK_ENV};
+use bws_connector::batch::Batch;
+use bws_connector::cache::{CacheMode, CacheSettings, SecretCache};
+use bws_connector::check::{self, CheckFormat};
+use bws_connector::client::build_headers;
+use bws_connector::config::{self, Config, Profile};
+use bws_connector::doctor;
+use bws_connector::expand::expand_secret;
+use bws_connector::format::Format;
+use bws_connector::jinja::render_jinja;
+use bws_connector::lock::Lockfile;
+use bws_connector::mask::Masker;
+use bws_connector::models::*;
+use bws_connector::render::{ResolveOptions, Template, read_input, render_template, resolve_keys};
+use bws_connector::run::{
+    env_from_process, exit_code, parse_env_assignment, parse_env_file, resolve_env, run_command,
+};
+use bws_connector::tls::TlsSettings;
+use bws_connector::token::{AccessToken, TokenSource};
+use bws_connector::{BwsClient, BwsError, ClientSettings};
+use cli::{
+    Cli, Commands, DEFAULT_API_URL, DEFAULT_BASE_URL, DEFAULT_IDENTITY_URL, Engine, ErrorFormat,
+};
 use output::print_response_with_parsed_value;
-use render::{read_input, render_template};
+
+/// Exit code for failures that are not a `BwsError`
+const EXIT_FAILURE: u8 = 1;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
-    
-    // Organization id is required via env var
-    let org_id = std::env::var("WARDEN_ORGANIZATION_ID")
-        .context("WARDEN_ORGANIZATION_ID must be set")?;
-    
-    // Build client and headers
-    let client = client::build_client(cli.insecure, &cli.ca_cert)?;
-    let headers = client::build_headers(
-        cli.access_token.clone(),
-        cli.api_url.clone(),
-        cli.identity_url.clone(),
-        cli.state_path.clone(),
-    )?;
+    let error_format = cli.error_format;
 
-    let base = cli.base_url.trim_end_matches('/').to_string();
+    match run(cli).await {
+        Ok(code) => code,
+        Err(err) => {
+            let bws_err = err.chain().find_map(|e| e.downcast_ref::<BwsError>());
+            let code = bws_err.map(BwsError::exit_code).unwrap_or(EXIT_FAILURE);
+            match error_format {
+                ErrorFormat::Text => eprintln!("Error: {:?}", err),
+                ErrorFormat::Json => {
+                    let json = match bws_err {
+                        Some(e) => e.to_json(),
+                        None => serde_json::json!({
+                            "kind": "error",
+                            "message": format!("{:#}", err),
+                            "status": null,
+                            "exit_code": code,
+                        }),
+                    };
+                    eprintln!("{}", serde_json::json!({ "error": json }));
+                }
+            }
+            ExitCode::from(code)
+        }
+    }
+}
 
-    match cli.command {
-        Commands::Get { id } => {
-            let req = SecretGetRequest { id };
-            let url = format!("{}/secret", base);
-            let resp = client
-                .get(&url)
-                .headers(headers)
-                .json(&req)
-                .send()
-                .await
-                .context("request failed")?;
-            let txt = resp.text().await?;
-            print_response_with_parsed_value(&txt, cli.parse_value, cli.field.as_deref())?;
+async fn run(cli: Cli) -> anyhow::Result<ExitCode> {
+    // Flags override environment variables, which override the profile
+    let profile = load_profile(&cli)?;
+    let settings = ClientSettings {
+        base_url: cli
+            .base_url
+            .clone()
+            .or(profile.base_url.clone())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+        token: match (cli.token_source(), env_var("WARDEN_ACCESS_TOKEN")) {
+            (Some(source), _) => Some(source),
+            (None, Some(token)) => Some(TokenSource::Value(token)),
+            (None, None) => profile.token_source()?,
+        },
+        api_url: cli
+            .api_url
+            .clone()
+            .or(profile.api_url.clone())
+            .or_else(|| Some(DEFAULT_API_URL.to_string())),
+        identity_url: cli
+            .identity_url
+            .clone()
+            .or(profile.identity_url.clone())
+            .or_else(|| Some(DEFAULT_IDENTITY_URL.to_string())),
+        state_path: cli.state_path.clone().or(profile.state_path.clone()),
+        tls: TlsSettings {
+            insecure: cli.insecure || profile.insecure.unwrap_or(false),
+            ca_certs: match cli.ca_cert.is_empty() {
+                true => profile.ca_cert.clone(),
+                false => cli.ca_cert.clone(),
+            },
+            client_cert: cli.client_cert.clone().or(profile.client_cert.clone()),
+            client_key: match &cli.client_cert {
+                Some(_) => cli.client_key.clone(),
+                None => profile.client_key.clone(),
+            },
+            client_cert_password: cli
+                .client_cert_password
+                .clone()
+                .or_else(|| env_var("BWS_CLIENT_CERT_PASSWORD"))
+                .or(profile.client_cert_password.clone()),
+            pins: match cli.pin_sha256.is_empty() {
+                true => profile.pin_sha256.clone(),
+                false => cli.pin_sha256.clone(),
+            },
+        },
+        proxy: cli.proxy.clone().or(profile.proxy.clone()),
+        no_proxy: cli.no_proxy.clone().or(profile.no_proxy.clone()),
+        connect_timeout: cli
+            .connect_timeout
+            .or(profile.connect_timeout)
+            .map(Duration::from_secs),
+        timeout: cli.timeout.or(profile.timeout).map(Duration::from_secs),
+        cache: cache_settings(&cli),
+    };
+
+    if let Commands::Agent { socket } = &cli.command {
+        let socket = socket.clone().unwrap_or_else(agent::default_socket_path);
+        let mut client = BwsClient::from_settings(&settings)?.verbose(cli.verbose);
+        if let Some(TokenSource::File(path)) = &settings.token {
+            client = client.with_token_file(path)?;
         }
-    Commands::GetByKey { key, organization_id } => {
-            // call list endpoint to find id by key
-            let url = format!("{}/secrets", base);
-            let org_to_use = organization_id.as_ref().map(|s| s.as_str()).unwrap_or(&org_id);
-            let req_builder = client.get(&url).headers(headers.clone());
-            let resp = req_builder.json(&serde_json::json!({"OrganizationID": org_to_use})).send().await.context("request failed")?;
-            let txt = resp.text().await?;
-            if cli.verbose {
-                eprintln!("raw list response: {}", txt);
+        if settings.cache.is_none() {
+            client = client.with_cache(SecretCache::in_memory(Duration::from_secs(cli.cache_ttl)));
+        }
+        agent::serve(client, &socket).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let org_id = OrgId(
+        cli.organization_id
+            .clone()
+            .or_else(|| env_var("WARDEN_ORGANIZATION_ID"))
+            .or(profile.organization_id),
+    );
+
+    if let Commands::Whoami = &cli.command {
+        return whoami(&settings, &org_id);
+    }
+    if let Commands::Doctor = &cli.command {
+        let report = doctor::diagnose(&settings, org_id.0.as_deref()).await;
+        println!("{}", report);
+        return Ok(match report.passed() {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::from(EXIT_FAILURE),
+        });
+    }
+
+    let client = match agent_socket(&cli.command) {
+        Some(socket) => BwsClient::from_agent(socket),
+        None => BwsClient::from_settings(&settings)?.verbose(cli.verbose),
+    };
+
+    match cli.command {
+        Commands::Get { id, recursive } => {
+            let mut secret = client.get(&id).await?;
+            if let Some(max_depth) = recursive.depth() {
+                secret =
+                    expand_secret(&client, org_id.get()?, secret, max_depth, cli.verbose).await?;
             }
-            let list: ListResponse = serde_json::from_str(&txt).context("failed to parse list response")?;
-            if cli.verbose {
-                let keys: Vec<String> = list.data.iter().map(|i| i.key.clone()).collect();
-                eprintln!("found keys: {:?}", keys);
+            print_response_with_parsed_value(&secret, cli.parse_value, cli.field.as_deref())?;
+        }
+        Commands::GetByKey {
+            key,
+            organization_id,
+            recursive,
+        } => {
+            let org_to_use = org_id.or(organization_id.as_deref())?;
+            let mut secret = client.get_by_key(&key, org_to_use).await?;
+            if let Some(max_depth) = recursive.depth() {
+                secret = expand_secret(&client, org_to_use, secret, max_depth, cli.verbose).await?;
             }
-            let found = list.data.into_iter().find(|i| i.key == key).ok_or_else(|| anyhow::anyhow!("secret with key not found"))?;
-
-            // fetch the secret by id
-            let get_req = SecretGetRequest { id: found.id };
-            let url = format!("{}/secret", base);
-            let resp = client
-                .get(&url)
-                .headers(headers)
-                .json(&get_req)
-                .send()
-                .await
-                .context("request failed")?;
-            let txt = resp.text().await?;
-            print_response_with_parsed_value(&txt, cli.parse_value, cli.field.as_deref())?;
+            print_response_with_parsed_value(&secret, cli.parse_value, cli.field.as_deref())?;
         }
         Commands::List { organization_id } => {
-            let url = format!("{}/secrets", base);
-            let org_to_use = organization_id.as_ref().map(|s| s.as_str()).unwrap_or(&org_id);
-            let resp = client.get(&url).headers(headers).json(&serde_json::json!({"OrganizationID": org_to_use})).send().await.context("request failed")?;
-            let txt = resp.text().await?;
-            if cli.verbose {
-                eprintln!("raw list response: {}", txt);
-                let list: ListResponse = serde_json::from_str(&txt).unwrap_or(ListResponse{data: vec![]});
-                let keys: Vec<String> = list.data.iter().map(|i| i.key.clone()).collect();
-                eprintln!("found keys: {:?}", keys);
-            }
-            print_response_with_parsed_value(&txt, cli.parse_value, cli.field.as_deref())?;
+            let org_to_use = org_id.or(organization_id.as_deref())?;
+            let list = client.list(org_to_use).await?;
+            print_response_with_parsed_value(&list, cli.parse_value, cli.field.as_deref())?;
         }
         Commands::GetByIds { ids } => {
-            let ids_vec: Vec<String> = ids.split(',').map(|s| s.trim().to_string()).collect();
-            let req = SecretsGetRequest { ids: ids_vec };
-            let url = format!("{}/secrets-by-ids", base);
-            let resp = client
-                .get(&url)
-                .headers(headers)
-                .json(&req)
-                .send()
-                .await
-                .context("request failed")?;
-            let txt = resp.text().await?;
-            print_response_with_parsed_value(&txt, cli.parse_value, cli.field.as_deref())?;
+            let secrets = client.get_by_ids(split_ids(&ids)).await?;
+            print_response_with_parsed_value(&secrets, cli.parse_value, cli.field.as_deref())?;
         }
-        Commands::Create { key, value, note, project_ids } => {
-            let pids = project_ids.map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
-            let req = SecretCreateRequest { key, value, note, organization_id: Some(org_id.clone()), project_ids: pids };
-            let url = format!("{}/secret", base);
-            let resp = client
-                .post(&url)
-                .headers(headers)
-                .json(&req)
-                .send()
-                .await
-                .context("request failed")?;
-            let txt = resp.text().await?;
-            print_response_with_parsed_value(&txt, cli.parse_value, cli.field.as_deref())?;
+        Commands::Create {
+            key,
+            value,
+            note,
+            project_ids,
+        } => {
+            let req = SecretCreateRequest {
+                key,
+                value,
+                note,
+                organization_id: Some(org_id.get()?.to_string()),
+                project_ids: project_ids.as_deref().map(split_ids),
+            };
+            let secret = client.create(&req).await?;
+            print_response_with_parsed_value(&secret, cli.parse_value, cli.field.as_deref())?;
         }
-        Commands::Update { id, key, value, note, project_ids } => {
-            let pids = project_ids.map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
-            let req = SecretPutRequest { id, key, value, note, organization_id: Some(org_id.clone()), project_ids: pids };
-            let url = format!("{}/secret", base);
-            let resp = client
-                .put(&url)
-                .headers(headers)
-                .json(&req)
-                .send()
-                .await
-                .context("request failed")?;
-            let txt = resp.text().await?;
-            print_response_with_parsed_value(&txt, cli.parse_value, cli.field.as_deref())?;
+        Commands::Update {
+            id,
+            key,
+            value,
+            note,
+            project_ids,
+        } => {
+            let req = SecretPutRequest {
+                id,
+                key,
+                value,
+                note,
+                organization_id: Some(org_id.get()?.to_string()),
+                project_ids: project_ids.as_deref().map(split_ids),
+            };
+            let secret = client.update(&req).await?;
+            print_response_with_parsed_value(&secret, cli.parse_value, cli.field.as_deref())?;
         }
         Commands::Delete { ids } => {
-            let ids_vec: Vec<String> = ids.split(',').map(|s| s.trim().to_string()).collect();
-            let req = SecretsDeleteRequest { ids: ids_vec };
-            let url = format!("{}/secret", base);
-            let resp = client
-                .delete(&url)
-                .headers(headers)
-                .json(&req)
-                .send()
+            let resp = client.delete(split_ids(&ids)).await?;
+            println!("{}", serde_json::to_string(&resp)?);
+        }
+        Commands::Render {
+            file,
+            strict,
+            input_dir,
+            output_dir,
+            files,
+            non_templates,
+            mode,
+            report,
+            lock,
+            frozen,
+            check,
+            check_format,
+            recursive,
+            env,
+            format,
+            engine,
+        } => {
+            let org_id = org_id.get()?;
+            let source = file
+                .as_ref()
+                .map_or_else(|| "<stdin>".to_string(), |f| f.display().to_string());
+            if engine == Engine::Jinja {
+                anyhow::ensure!(
+                    report.is_none()
+                        && lock.is_none()
+                        && !check
+                        && recursive.depth().is_none()
+                        && env.overlay()?.is_none()
+                        && format.is_none()
+                        && input_dir.is_none()
+                        && files.is_empty(),
+                    "--report, --lock, --check, --recursive, --env, --format, --input-dir and --file apply to the bws engine only"
+                );
+                let output =
+                    render_jinja(&client, org_id, source, read_input(file)?, cli.verbose).await?;
+                print!("{}", output);
+                return Ok(ExitCode::SUCCESS);
+            }
+            let options = ResolveOptions {
+                recursive: recursive.depth(),
+                overlay: env.overlay()?,
+            };
+            if input_dir.is_some() || !files.is_empty() {
+                let mut batch = Batch::new();
+                if let Some(input_dir) = &input_dir {
+                    // --check writes nothing, so it needs no output directory
+                    let output_dir = match output_dir {
+                        Some(dir) => dir,
+                        None if check => input_dir.clone(),
+                        None => anyhow::bail!("--input-dir needs --output-dir"),
+                    };
+                    batch.add_dir(input_dir, &output_dir, format, non_templates)?;
+                }
+                for (input, output) in files {
+                    batch.add_file(input, output, format)?;
+                }
+                if check {
+                    return check_templates(
+                        batch.templates(),
+                        &client,
+                        org_id,
+                        &options,
+                        check_format,
+                    )
+                    .await;
+                }
+                let rendered = batch.render(&client, org_id, &options, cli.verbose).await?;
+                if let Some(path) = report {
+                    let json = serde_json::to_string_pretty(&rendered.report())?;
+                    std::fs::write(&path, json).context("writing render report")?;
+                }
+                if strict {
+                    rendered.ensure_resolved()?;
+                } else {
+                    rendered.ensure_required()?;
+                }
+                if let Some(path) = &lock {
+                    check_lock(
+                        path,
+                        frozen,
+                        Lockfile::from_placeholders(rendered.placeholders())
+                            .with_nested(rendered.nested()),
+                    )?;
+                }
+                for unresolved in rendered.unresolved() {
+                    eprintln!("warning: {}", unresolved);
+                }
+                rendered.write(mode)?;
+                let (templates, copied) = rendered.counts();
+                eprintln!("rendered {} file(s), copied {}", templates, copied);
+                return Ok(ExitCode::SUCCESS);
+            }
+            let format = format
+                .or_else(|| file.as_deref().map(Format::from_path))
+                .unwrap_or_default();
+            let template = Template::parse_as(source, read_input(file)?, format);
+            if check {
+                return check_templates([&template], &client, org_id, &options, check_format).await;
+            }
+            let rendered =
+                render_template(&template, &client, org_id, &options, cli.verbose).await?;
+            if let Some(path) = report {
+                let json = serde_json::to_string_pretty(&rendered.report())?;
+                std::fs::write(&path, json).context("writing render report")?;
+            }
+            if strict {
+                rendered.ensure_resolved()?;
+            } else {
+                rendered.ensure_required()?;
+            }
+            if let Some(path) = &lock {
+                check_lock(
+                    path,
+                    frozen,
+                    Lockfile::from_placeholders(&rendered.placeholders)
+                        .with_nested(&rendered.nested),
+                )?;
+            }
+            for unresolved in rendered.unresolved() {
+                eprintln!("warning: {}", unresolved);
+            }
+            println!("{}", rendered.output);
+        }
+        Commands::Run {
+            env,
+            env_files,
+            mask,
+            command,
+        } => {
+            let mut vars = env_from_process();
+            for path in &env_files {
+                vars.extend(parse_env_file(path)?);
+            }
+            for assignment in &env {
+                vars.push(parse_env_assignment(assignment)?);
+            }
+            let resolved = resolve_env(&client, org_id.get()?, vars, cli.verbose).await?;
+            let masker = mask.then(|| resolved.masker());
+            let status = run_command(&command, &resolved.vars, masker.as_ref()).await?;
+            return Ok(ExitCode::from(exit_code(status)));
+        }
+        Commands::Mask { ids, keys } => {
+            anyhow::ensure!(
+                ids.is_some() || keys.is_some(),
+                "mask needs --ids and/or --keys"
+            );
+            let mut secrets = Vec::new();
+            if let Some(ids) = ids {
+                secrets.extend(client.get_by_ids(split_ids(&ids)).await?.data);
+            }
+            if let Some(keys) = keys {
+                let resolution = resolve_keys(
+                    &client,
+                    org_id.get()?,
+                    &split_ids(&keys).into_iter().collect(),
+                )
+                .await?;
+                for key in split_ids(&keys) {
+                    if let Some(Err(e)) = resolution.get(&key) {
+                        eprintln!("warning: {}", e);
+                    }
+                }
+                secrets.extend(resolution.secrets().cloned());
+            }
+            Masker::for_secrets(&secrets)
+                .copy(tokio::io::stdin(), tokio::io::stdout())
                 .await
-                .context("request failed")?;
-            let txt = resp.text().await?;
-            println!("{}", txt);
+                .context("masking stdin")?;
         }
-        Commands::Render { file } => {
-            let input = read_input(file)?;
-            let output = render_template(
-                input,
-                &client,
-                &headers,
-                &base,
-                &org_id,
-                cli.verbose,
-            )
-            .await?;
-            println!("{}", output);
+        Commands::Agent { .. } | Commands::Whoami | Commands::Doctor => {
+            unreachable!("handled above")
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+/// `whoami`: parse the access token locally and print what would be sent, without any request
+fn whoami(settings: &ClientSettings, org_id: &OrgId) -> anyhow::Result<ExitCode> {
+    let source = settings.token.as_ref().context(
+        "access token missing; set WARDEN_ACCESS_TOKEN, an --access-token* flag or a token source in the profile",
+    )?;
+    let token = source.read()?;
+    let parsed = AccessToken::parse(&token)?;
+    let headers = build_headers(
+        Some(token),
+        settings.api_url.clone(),
+        settings.identity_url.clone(),
+        settings.state_path.clone(),
+    )?;
+
+    println!("token source:    {}", source);
+    println!("token version:   {}", parsed.version);
+    println!("access token id: {}", parsed.access_token_id);
+    println!("fingerprint:     {}", parsed.fingerprint);
+    println!(
+        "organization id: {}",
+        org_id.0.as_deref().unwrap_or("(not set)")
+    );
+    println!("base url:        {}", settings.base_url);
+    println!("headers:");
+    for name in [
+        "Warden-Access-Token",
+        "Warden-Api-Url",
+        "Warden-Identity-Url",
+        "Warden-State-Path",
+    ] {
+        let value = match headers.get(name) {
+            None => "(not sent)".to_string(),
+            Some(_) if name == "Warden-Access-Token" => parsed.redacted(),
+            Some(value) => value.to_str().unwrap_or("(not text)").to_string(),
+        };
+        println!("  {}: {}", name, value);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The organization id, required only by the commands that use it
+struct OrgId(Option<String>);
+
+impl OrgId {
+    fn get(&self) -> anyhow::Result<&str> {
+        self.0.as_deref().context(
+            "organization id missing; set --organization-id, WARDEN_ORGANIZATION_ID or organization_id in the profile",
+        )
+    }
+
+    /// A per-command `--organization-id`, or the global one
+    fn or<'a>(&'a self, organization_id: Option<&'a str>) -> anyhow::Result<&'a str> {
+        organization_id.map_or_else(|| self.get(), Ok)
+    }
+}
+
+/// The selected profile from the config file, or an empty one when there is none
+fn load_profile(cli: &Cli) -> anyhow::Result<Profile> {
+    let name = cli.profile.clone().or_else(|| env_var("BWS_PROFILE"));
+    let config = match cli
+        .config
+        .clone()
+        .or_else(|| env_var("BWS_CONFIG").map(Into::into))
+    {
+        // an explicit config file must exist
+        Some(path) => Config::load(&path)?,
+        None => match config::default_path() {
+            Some(path) => Config::load_or_default(&path)?,
+            None => Config::default(),
+        },
+    };
+    config.profile(name.as_deref())
+}
+
+/// An environment variable that is set and not empty
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Cache settings from the global flags; `--offline` and `--stale-if-error` imply `--cache`
+fn cache_settings(cli: &Cli) -> Option<CacheSettings> {
+    let mode = if cli.offline {
+        CacheMode::Offline
+    } else if cli.stale_if_error {
+        CacheMode::StaleIfError
+    } else if cli.cache {
+        CacheMode::Normal
+    } else {
+        return None;
+    };
+    Some(CacheSettings {
+        dir: cli.cache_dir.clone(),
+        ttl: Duration::from_secs(cli.cache_ttl),
+        mode,
+    })
+}
+
+/// The agent socket from `BWS_AGENT_SOCK`, for commands that only read secrets
+fn agent_socket(command: &Commands) -> Option<String> {
+    let read_only = matches!(
+        command,
+        Commands::Get { .. }
+            | Commands::GetByKey { .. }
+            | Commands::List { .. }
+            | Commands::GetByIds { .. }
+            | Commands::Render { .. }
+            | Commands::Run { .. }
+            | Commands::Mask { .. }
+    );
+    std::env::var(AGENT_SOCK_ENV)
+        .ok()
+        .filter(|s| read_only && !s.is_empty())
+}
+
+/// `render --check`: print the findings, failing if there are any
+async fn check_templates<'a>(
+    templates: impl IntoIterator<Item = &'a Template>,
+    client: &BwsClient,
+    org_id: &str,
+    options: &ResolveOptions,
+    format: CheckFormat,
+) -> anyhow::Result<ExitCode> {
+    let report = check::check(templates, client, org_id, options.overlay.as_ref()).await?;
+    match format {
+        CheckFormat::Text => println!("{}", report),
+        CheckFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    report.ensure_clean()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Write the lock file, or with `--frozen` fail if the secrets used differ from it
+fn check_lock(path: &Path, frozen: bool, current: Lockfile) -> anyhow::Result<()> {
+    if frozen {
+        Lockfile::read(path)?.verify(&current)?;
+        Ok(())
+    } else {
+        current.write(path)
+    }
+}
+
+/// Split a comma separated id list
+fn split_ids(ids: &str) -> Vec<String> {
+    ids.split(',').map(|s| s.trim().to_string()).collect()
 }
@@ -0,0 +1,185 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::Value as JsonValue;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::models::SecretResponse;
+
+/// Text written in place of a concealed secret
+pub const CONCEALED: &str = "<concealed by bws>";
+
+/// Values shorter than this are not masked, to avoid concealing every `1` or `on` in the output
+const MIN_MASK_LEN: usize = 3;
+
+/// Redacts known secret values, plus their base64 and URL-encoded forms, from byte streams
+#[derive(Clone, Debug, Default)]
+pub struct Masker {
+    /// Byte patterns to conceal, longest first so the longest match wins
+    needles: Vec<Vec<u8>>,
+}
+
+impl Masker {
+    /// Build a masker for the given plain-text values
+    pub fn new<I, S>(values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut needles: Vec<Vec<u8>> = Vec::new();
+        for value in values {
+            let value = value.as_ref();
+            if value.len() < MIN_MASK_LEN {
+                continue;
+            }
+            needles.push(value.as_bytes().to_vec());
+            needles.push(BASE64.encode(value).into_bytes());
+            needles.push(percent_encode(value).into_bytes());
+        }
+        needles.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        needles.dedup();
+        Self { needles }
+    }
+
+    /// Build a masker for secrets: the raw value and, for JSON values, every string leaf
+    pub fn for_secrets<'a>(secrets: impl IntoIterator<Item = &'a SecretResponse>) -> Self {
+        Self::new(secrets.into_iter().flat_map(secret_strings))
+    }
+
+    /// Start filtering a new stream
+    pub fn filter(&self) -> MaskFilter<'_> {
+        MaskFilter {
+            masker: self,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Copy `reader` to `writer`, concealing secrets. Output is flushed after every read so
+    /// interactive streams stay interactive.
+    pub async fn copy<R, W>(&self, mut reader: R, mut writer: W) -> std::io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut filter = self.filter();
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&filter.push(&buf[..n])).await?;
+            writer.flush().await?;
+        }
+        writer.write_all(&filter.finish()).await?;
+        writer.flush().await
+    }
+}
+
+/// Streaming state for one output stream.
+///
+/// Bytes that could be the start of a secret are held back until enough input arrives to
+/// decide, so secrets split across reads are still concealed.
+pub struct MaskFilter<'a> {
+    masker: &'a Masker,
+    pending: Vec<u8>,
+}
+
+impl MaskFilter<'_> {
+    /// Feed a chunk and return the output that is safe to emit
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        let buf = std::mem::take(&mut self.pending);
+        let mut out = Vec::with_capacity(buf.len());
+        let mut i = 0;
+        while i < buf.len() {
+            let rest = &buf[i..];
+            if let Some(needle) = self.masker.needles.iter().find(|n| rest.starts_with(n)) {
+                out.extend_from_slice(CONCEALED.as_bytes());
+                i += needle.len();
+            } else if self
+                .masker
+                .needles
+                .iter()
+                .any(|n| n.len() > rest.len() && n.starts_with(rest))
+            {
+                self.pending = rest.to_vec();
+                break;
+            } else {
+                out.push(buf[i]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    /// Flush held-back bytes at end of stream
+    pub fn finish(self) -> Vec<u8> {
+        self.pending
+    }
+}
+
+/// Percent-encode everything except RFC 3986 unreserved characters
+pub fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// The strings of a secret worth concealing: its raw value and, for JSON values, every string leaf
+pub fn secret_strings(secret: &SecretResponse) -> Vec<String> {
+    let mut values = vec![secret.value.clone()];
+    collect_strings(&secret.parsed_value(), &mut values);
+    values
+}
+
+fn collect_strings(value: &JsonValue, out: &mut Vec<String>) {
+    match value {
+        JsonValue::String(s) => out.push(s.clone()),
+        JsonValue::Array(items) => items.iter().for_each(|v| collect_strings(v, out)),
+        JsonValue::Object(map) => map.values().for_each(|v| collect_strings(v, out)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_chunks(masker: &Masker, chunks: &[&str]) -> String {
+        let mut filter = masker.filter();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(filter.push(chunk.as_bytes()));
+        }
+        out.extend(filter.finish());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_mask_value_and_encodings() {
+        let masker = Masker::new(["p@ss word"]);
+        let out = mask_chunks(
+            &masker,
+            &["pass=p@ss word b64=cEBzcyB3b3Jk url=p%40ss%20word"],
+        );
+        assert_eq!(
+            out,
+            "pass=<concealed by bws> b64=<concealed by bws> url=<concealed by bws>"
+        );
+    }
+
+    #[test]
+    fn test_mask_across_chunks() {
+        let masker = Masker::new(["hunter2"]);
+        assert_eq!(
+            mask_chunks(&masker, &["the password is hun", "ter2!", " hunt"]),
+            "the password is <concealed by bws>! hunt"
+        );
+    }
+}
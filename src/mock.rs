@@ -0,0 +1,135 @@
+//! A local stand-in for the sdk-server, for unit tests that go through [`BwsClient`]
+
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::client::BwsClient;
+
+/// One request as the mock server saw it
+pub(crate) struct Request {
+    pub method: String,
+    /// The path below `/rest/api/1/`, e.g. `secrets-by-ids`
+    pub path: String,
+    /// The header lines, lowercased
+    pub headers: String,
+    pub body: serde_json::Value,
+}
+
+/// Serve on a free local port, answering each request with the status line and body `respond`
+/// returns
+pub(crate) async fn serve<F>(respond: F) -> u16
+where
+    F: Fn(&Request) -> (&'static str, String) + Send + Sync + 'static,
+{
+    let respond = Arc::new(respond);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // read the headers and the (small) body
+                let (head, body) = loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text[..end]
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(str::to_string)
+                            })
+                            .and_then(|l| l.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + length || n == 0 {
+                            break (text[..end].to_string(), text[end + 4..].to_string());
+                        }
+                    }
+                    if n == 0 {
+                        return;
+                    }
+                };
+                let mut start = head.split_whitespace();
+                let method = start.next().unwrap_or_default().to_string();
+                let target = start.next().unwrap_or_default();
+                let request = Request {
+                    method,
+                    path: target.trim_start_matches("/rest/api/1/").to_string(),
+                    headers: head.to_lowercase(),
+                    body: serde_json::from_str(&body).unwrap_or_default(),
+                };
+                let (status, body) = respond(&request);
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    port
+}
+
+/// Serve `(id, key, value)` secrets of the organization "org" from the list, secret and
+/// secrets-by-ids endpoints. Unknown ids are a 404, as the sdk-server answers them.
+pub(crate) async fn with_secrets(secrets: &[(&str, &str, &str)]) -> u16 {
+    let secrets: Vec<serde_json::Value> = secrets
+        .iter()
+        .map(|(id, key, value)| secret_json(id, key, value))
+        .collect();
+    serve(move |req| {
+        let find = |id: &serde_json::Value| secrets.iter().find(|s| &s["id"] == id).cloned();
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "secrets") => {
+                let list: Vec<_> = secrets
+                    .iter()
+                    .map(|s| serde_json::json!({"id": s["id"], "key": s["key"], "organizationId": "org"}))
+                    .collect();
+                ("200 OK", serde_json::json!({ "data": list }).to_string())
+            }
+            ("GET", "secret") => match find(&req.body["ID"]) {
+                Some(secret) => ("200 OK", secret.to_string()),
+                None => ("404 Not Found", "secret not found".to_string()),
+            },
+            ("GET", "secrets-by-ids") => {
+                let ids = req.body["IDS"].as_array().cloned().unwrap_or_default();
+                match ids.iter().map(find).collect::<Option<Vec<_>>>() {
+                    Some(data) => ("200 OK", serde_json::json!({ "data": data }).to_string()),
+                    None => ("404 Not Found", "secret not found".to_string()),
+                }
+            }
+            _ => ("400 Bad Request", "unexpected request".to_string()),
+        }
+    })
+    .await
+}
+
+/// A secret as the sdk-server returns it
+pub(crate) fn secret_json(id: &str, key: &str, value: &str) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "organizationId": "org",
+        "projectId": null,
+        "key": key,
+        "value": value,
+        "note": "",
+        "creationDate": "",
+        "revisionDate": "",
+    })
+}
+
+/// A client for the mock server on `port`
+pub(crate) fn client(port: u16) -> BwsClient {
+    BwsClient::new(
+        reqwest::Client::new(),
+        Default::default(),
+        &format!("http://127.0.0.1:{}/rest/api/1", port),
+    )
+}
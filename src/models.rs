@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 
 #[derive(Serialize)]
 pub struct SecretGetRequest {
@@ -12,18 +13,48 @@ pub struct SecretsGetRequest {
     pub ids: Vec<String>,
 }
 
-#[derive(Deserialize)]
-pub struct ListItem {
+/// Identifier entry returned by the list endpoint (no value)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretIdentifierResponse {
     pub id: String,
-    #[allow(dead_code)]
-    #[serde(rename = "organizationId")]
-    pub organization_id: Option<String>,
+    pub organization_id: String,
     pub key: String,
 }
 
-#[derive(Deserialize)]
-pub struct ListResponse {
-    pub data: Vec<ListItem>,
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SecretIdentifiersResponse {
+    pub data: Vec<SecretIdentifierResponse>,
+}
+
+/// A full secret as returned by get, create, update and secrets-by-ids
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretResponse {
+    pub id: String,
+    pub organization_id: String,
+    #[serde(default)]
+    pub project_id: Option<String>,
+    pub key: String,
+    pub value: String,
+    #[serde(default)]
+    pub note: String,
+    /// RFC 3339 timestamp
+    pub creation_date: String,
+    /// RFC 3339 timestamp
+    pub revision_date: String,
+}
+
+impl SecretResponse {
+    /// The secret value parsed as JSON, or the raw value as a JSON string if it is not JSON
+    pub fn parsed_value(&self) -> JsonValue {
+        serde_json::from_str(&self.value).unwrap_or_else(|_| JsonValue::String(self.value.clone()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SecretsResponse {
+    pub data: Vec<SecretResponse>,
 }
 
 #[derive(Serialize)]
@@ -32,6 +63,17 @@ pub struct SecretsDeleteRequest {
     pub ids: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SecretDeleteResponse {
+    pub id: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SecretsDeleteResponse {
+    pub data: Vec<SecretDeleteResponse>,
+}
+
 #[derive(Serialize)]
 pub struct SecretCreateRequest {
     pub key: String,
@@ -54,3 +96,30 @@ pub struct SecretPutRequest {
     #[serde(rename = "ProjectIDS")]
     pub project_ids: Option<Vec<String>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_secret_response() {
+        let txt = r#"{
+            "id": "1b9e2a52-0000-0000-0000-000000000001",
+            "organizationId": "org",
+            "projectId": null,
+            "key": "harbor",
+            "value": "{\"pg_user\": \"harbor\"}",
+            "note": "",
+            "creationDate": "2024-01-02T03:04:05.000Z",
+            "revisionDate": "2024-02-03T04:05:06.000Z"
+        }"#;
+        let secret: SecretResponse = serde_json::from_str(txt).expect("parse secret");
+        assert_eq!(secret.key, "harbor");
+        assert!(secret.project_id.is_none());
+        assert_eq!(secret.revision_date, "2024-02-03T04:05:06.000Z");
+        assert_eq!(
+            secret.parsed_value()["pg_user"],
+            serde_json::json!("harbor")
+        );
+    }
+}
@@ -1,56 +1,32 @@
+use serde::Serialize;
 use serde_json::Value as JsonValue;
 
-use crate::render::extract_path;
+use bws_connector::render::extract_path;
 
 /// Print a response, optionally parsing value field as JSON and extracting a specific field
-pub fn print_response_with_parsed_value(
-    txt: &str,
+pub fn print_response_with_parsed_value<T: Serialize>(
+    resp: &T,
     parse_value: bool,
     field: Option<&str>,
 ) -> anyhow::Result<()> {
+    let mut v: JsonValue = serde_json::to_value(resp)?;
+
     // If no JSON parsing requested and no field extraction, just print raw
     if !parse_value && field.is_none() {
-        println!("{}", txt);
+        println!("{}", v);
         return Ok(());
     }
 
-    // Try to parse top-level response JSON
-    let mut v: JsonValue = match serde_json::from_str(txt) {
-        Ok(j) => j,
-        Err(_) => {
-            if field.is_some() {
-                return Err(anyhow::anyhow!("response is not valid JSON, cannot extract field"));
-            }
-            // not JSON and no field requested: print raw
-            println!("{}", txt);
-            return Ok(());
-        }
-    };
-
     // If there's a `value` string, try to parse it as JSON and replace it with parsed JSON
     if let Some(val) = v.get_mut("value") {
-        if val.is_string() {
-            if let Some(s) = val.as_str() {
-                if let Ok(parsed) = serde_json::from_str::<JsonValue>(s) {
-                    *val = parsed;
-                }
-            }
-        }
+        parse_json_string(val);
     }
 
     // If `data` array exists, attempt same transformation on each element
-    if let Some(data) = v.get_mut("data") {
-        if let Some(array) = data.as_array_mut() {
-            for item in array.iter_mut() {
-                if let Some(val) = item.get_mut("value") {
-                    if val.is_string() {
-                        if let Some(s) = val.as_str() {
-                            if let Ok(parsed) = serde_json::from_str::<JsonValue>(s) {
-                                *val = parsed;
-                            }
-                        }
-                    }
-                }
+    if let Some(array) = v.get_mut("data").and_then(|d| d.as_array_mut()) {
+        for item in array.iter_mut() {
+            if let Some(val) = item.get_mut("value") {
+                parse_json_string(val);
             }
         }
     }
@@ -64,19 +40,13 @@ pub fn print_response_with_parsed_value(
         }
 
         // If not found top-level, try data array first-match
-        if extracted.is_none() {
-            if let Some(data) = v.get("data") {
-                if let Some(array) = data.as_array() {
-                    for item in array.iter() {
-                        if let Some(val) = item.get("value") {
-                            if let Some(found) = extract_path(val, path) {
-                                extracted = Some(found);
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
+        if extracted.is_none()
+            && let Some(array) = v.get("data").and_then(|d| d.as_array())
+        {
+            extracted = array
+                .iter()
+                .filter_map(|item| item.get("value"))
+                .find_map(|val| extract_path(val, path));
         }
 
         if let Some(found) = extracted {
@@ -92,16 +62,21 @@ pub fn print_response_with_parsed_value(
         }
     }
 
-    // If parsing was requested but no specific field, print full parsed JSON
-    if parse_value {
-        println!("{}", serde_json::to_string_pretty(&v)?);
-    } else {
-        println!("{}", txt);
-    }
+    // Parsing was requested but no specific field, print full parsed JSON
+    println!("{}", serde_json::to_string_pretty(&v)?);
 
     Ok(())
 }
 
+/// Replace a JSON string holding an encoded JSON document with the parsed document
+fn parse_json_string(val: &mut JsonValue) {
+    if let Some(s) = val.as_str()
+        && let Ok(parsed) = serde_json::from_str::<JsonValue>(s)
+    {
+        *val = parsed;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -111,17 +86,31 @@ mod tests {
         // outer JSON has 'value' as a JSON-encoded string
         let txt = r#"{"value":"{\"inner\": {\"k\": \"v\"}}"}"#;
         let mut v: JsonValue = serde_json::from_str(txt).expect("parse outer");
-        if let Some(val) = v.get_mut("value") {
-            if val.is_string() {
-                if let Some(s) = val.as_str() {
-                    let parsed: JsonValue = serde_json::from_str(s).expect("parse inner");
-                    *val = parsed;
-                }
-            }
+        if let Some(val) = v.get_mut("value")
+            && val.is_string()
+            && let Some(s) = val.as_str()
+        {
+            let parsed: JsonValue = serde_json::from_str(s).expect("parse inner");
+            *val = parsed;
         }
 
         // now extract inner.k
         let got = extract_path(&v["value"], "inner.k").expect("found path");
         assert_eq!(got, serde_json::json!("v"));
     }
+
+    #[test]
+    fn test_parse_json_string() {
+        let mut v = serde_json::json!("{\"inner\": {\"k\": \"v\"}}");
+        parse_json_string(&mut v);
+        assert_eq!(extract_path(&v, "inner.k"), Some(serde_json::json!("v")));
+
+        // a plain string, or a value that is not a string, is left as it is
+        let mut plain = serde_json::json!("not json");
+        parse_json_string(&mut plain);
+        assert_eq!(plain, serde_json::json!("not json"));
+        let mut number = serde_json::json!(5);
+        parse_json_string(&mut number);
+        assert_eq!(number, serde_json::json!(5));
+    }
 }
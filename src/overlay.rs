@@ -0,0 +1,97 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// How `render --env` names the per-environment variant of a key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvRule {
+    /// `prod_harbor`
+    #[default]
+    Prefix,
+    /// `harbor_prod`
+    Suffix,
+}
+
+/// Maps a logical key such as `harbor` to its per-environment key such as `prod_harbor`.
+///
+/// The environment key is used when it exists; otherwise the base key is, so secrets shared by
+/// every environment need no copies.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "rule", rename_all = "lowercase")]
+pub enum Overlay {
+    /// `<env><separator><key>` or `<key><separator><env>`
+    Naming {
+        env: String,
+        position: EnvRule,
+        separator: String,
+    },
+    /// Explicit keys from a map file; unmapped keys use the base key
+    Map { keys: BTreeMap<String, String> },
+}
+
+impl Overlay {
+    pub fn naming(env: &str, position: EnvRule, separator: &str) -> Self {
+        Self::Naming {
+            env: env.to_string(),
+            position,
+            separator: separator.to_string(),
+        }
+    }
+
+    /// Load the `[env]` table of a TOML map file:
+    ///
+    /// ```toml
+    /// [prod]
+    /// harbor = "harbor-production"
+    /// ```
+    pub fn from_map_file(path: &Path, env: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading env map {}", path.display()))?;
+        let mut envs: BTreeMap<String, BTreeMap<String, String>> =
+            toml::from_str(&text).with_context(|| format!("parsing env map {}", path.display()))?;
+        let keys = envs
+            .remove(env)
+            .with_context(|| format!("no [{}] table in {}", env, path.display()))?;
+        Ok(Self::Map { keys })
+    }
+
+    /// The environment-specific key to try before `key`
+    pub fn key_for(&self, key: &str) -> Option<String> {
+        match self {
+            Self::Naming {
+                env,
+                position: EnvRule::Prefix,
+                separator,
+            } => Some(format!("{}{}{}", env, separator, key)),
+            Self::Naming {
+                env,
+                position: EnvRule::Suffix,
+                separator,
+            } => Some(format!("{}{}{}", key, separator, env)),
+            Self::Map { keys } => keys.get(key).cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlay_keys() {
+        let prefix = Overlay::naming("prod", EnvRule::Prefix, "_");
+        assert_eq!(prefix.key_for("harbor").unwrap(), "prod_harbor");
+        let suffix = Overlay::naming("prod", EnvRule::Suffix, "-");
+        assert_eq!(suffix.key_for("harbor").unwrap(), "harbor-prod");
+
+        let path = std::env::temp_dir().join(format!("bws-env-map-{}.toml", std::process::id()));
+        std::fs::write(&path, "[prod]\nharbor = \"harbor-production\"\n[dev]\n").unwrap();
+        let map = Overlay::from_map_file(&path, "prod").unwrap();
+        assert_eq!(map.key_for("harbor").unwrap(), "harbor-production");
+        assert_eq!(map.key_for("dsn"), None);
+        assert!(Overlay::from_map_file(&path, "staging").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
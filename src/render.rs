@@ -1,148 +1,901 @@
 use anyhow::Context;
 use regex::Regex;
-use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::path::PathBuf;
+use std::sync::LazyLock;
 
-use crate::models::{ListResponse, SecretGetRequest};
+use crate::agent::{self, AgentRequest};
+use crate::client::BwsClient;
+use crate::error::BwsError;
+use crate::filter::{self, Filter};
+use crate::format::{self, Format, Slot};
+use crate::models::{SecretIdentifierResponse, SecretResponse};
+use crate::overlay::Overlay;
 
-/// Render placeholders in input text, replacing bws://key[/path] with secret values
-pub async fn render_template(
-    input: String,
-    client: &reqwest::Client,
-    headers: &HeaderMap,
-    base_url: &str,
-    org_id: &str,
-    verbose: bool,
-) -> anyhow::Result<String> {
-    let re = Regex::new(r"bws://([A-Za-z0-9_\-]+)(?:/([A-Za-z0-9_./-]+))?").unwrap();
-    let mut out = input.clone();
-
-    for cap in re.captures_iter(&input) {
-        let placeholder = cap.get(0).unwrap().as_str();
-        let key = cap.get(1).map(|m| m.as_str()).unwrap();
-        let path = cap.get(2).map(|m| m.as_str());
-        let ph_start = cap.get(0).unwrap().start();
-        let ph_end = cap.get(0).unwrap().end();
-
-        // Fetch list to find secret id by key
-        let url = format!("{}/secrets", base_url);
-        let resp = client
-            .get(&url)
-            .headers(headers.clone())
-            .json(&serde_json::json!({"OrganizationID": org_id}))
-            .send()
-            .await
-            .context("request failed")?;
-        
-        let txt = resp.text().await?;
-        if verbose {
-            eprintln!("raw list response: {}", txt);
-        }
-
-        let list: ListResponse = serde_json::from_str(&txt).context("failed to parse list response")?;
-        if verbose {
-            let keys: Vec<String> = list.data.iter().map(|i| i.key.clone()).collect();
-            eprintln!("found keys: {:?}", keys);
-        }
-
-        if let Some(found) = list.data.into_iter().find(|i| i.key == key) {
-            let get_req = SecretGetRequest { id: found.id };
-            let url = format!("{}/secret", base_url);
-            let resp = client
-                .get(&url)
-                .headers(headers.clone())
-                .json(&get_req)
-                .send()
-                .await
-                .context("request failed")?;
-            
-            let secret_txt = resp.text().await?;
-            
-            if let Ok(json) = serde_json::from_str::<JsonValue>(&secret_txt) {
-                // Prefer the `value` field, otherwise use the whole JSON
-                let val_ref = json.get("value").unwrap_or(&json);
-
-                // If `value` is a string that contains JSON, try to parse it
-                let parsed_val: JsonValue = if val_ref.is_string() {
-                    if let Some(s) = val_ref.as_str() {
-                        match serde_json::from_str::<JsonValue>(s) {
-                            Ok(inner) => inner,
-                            Err(_) => val_ref.clone(),
-                        }
-                    } else {
-                        val_ref.clone()
+/// Maximum number of ids sent in a single secrets-by-ids request
+pub(crate) const BATCH_SIZE: usize = 100;
+
+/// Placeholder forms, tried at each position in order:
+///
+/// - `bws:://` or `$${bws:`: an escaped literal, rendered as `bws://` or `${bws:`. Neither is an
+///   escape sequence in JSON, YAML or TOML strings, so escapes survive those formats unchanged
+/// - `bws://id:<id>[/path]` or `bws://key[/path]`, then `[?modifier[=value]][|filter...]`
+/// - `${bws:"quoted key"[#path][?modifier[=value]][|filter...]}` or `${bws:id:<id>...}`
+static PLACEHOLDER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?x)
+        (?P<escape>bws:://|\$\$\{bws:)
+        | bws://(?:id:(?P<id>[0-9A-Za-z-]+)|(?P<key>[A-Za-z0-9_\-]+))
+          (?:/(?P<path>[A-Za-z0-9_./-]+))?
+          (?:\?(?P<modifier>[A-Za-z_-]+)(?:=(?P<value>[^\s"'`,;<>(){}\[\]|]*))?)?
+          (?P<filters>(?:\|[A-Za-z0-9_-]+)*)
+        | \$\{bws:(?:"(?P<qkey>(?:[^"\\\n]|\\.)*)"|id:(?P<qid>[0-9A-Za-z-]+))
+          (?:\#(?P<qpath>[^?|}\n]+))?
+          (?:\?(?P<qmodifier>[A-Za-z_-]+)(?:=(?P<qvalue>[^|}\n]*))?)?
+          (?P<qfilters>(?:\|[A-Za-z0-9_-]+)*)
+          \}
+        "#,
+    )
+    .unwrap()
+});
+
+/// What to do when a placeholder's key or path does not exist
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Modifier {
+    /// Leave the placeholder and report it (`--strict` turns this into a failure)
+    #[default]
+    None,
+    /// `?default=value`: substitute `value` (percent-decoded)
+    Default(String),
+    /// `?optional`: substitute an empty string
+    Optional,
+    /// `?required`: fail the render even without `--strict`
+    Required,
+}
+
+impl Modifier {
+    fn parse(name: &str, value: Option<&str>) -> Result<Self, String> {
+        match (name, value) {
+            ("default", Some(value)) => Ok(Self::Default(percent_decode(value))),
+            ("optional", None) => Ok(Self::Optional),
+            ("required", None) => Ok(Self::Required),
+            ("default", None) => Err("modifier 'default' needs a value (?default=...)".to_string()),
+            ("optional" | "required", Some(_)) => {
+                Err(format!("modifier '{}' takes no value", name))
+            }
+            _ => Err(format!(
+                "unknown modifier '{}', expected default=..., optional or required",
+                name
+            )),
+        }
+    }
+}
+
+/// A bws://key[/path] reference found in a template
+#[derive(Clone, Debug, PartialEq)]
+pub struct Placeholder {
+    /// Byte offset of the placeholder start in the template
+    pub start: usize,
+    /// Byte offset just past the placeholder end
+    pub end: usize,
+    /// 1-based line of the placeholder start
+    pub line: usize,
+    /// 1-based column (in characters) of the placeholder start
+    pub column: usize,
+    /// The secret key, or its id when `by_id` is set
+    pub key: String,
+    /// Written in the ID form (`bws://id:...`), so no list lookup is needed
+    pub by_id: bool,
+    pub path: Option<String>,
+    pub modifier: Modifier,
+    /// Applied in order to the extracted (or default) value
+    pub filters: Vec<Filter>,
+    /// Why the placeholder is malformed; it is reported instead of resolved
+    pub invalid: Option<String>,
+}
+
+/// Find every placeholder in the input
+pub fn find_placeholders(input: &str) -> Vec<Placeholder> {
+    scan(input).0
+}
+
+/// Find placeholders and the byte offsets of the characters dropped from escaped literals
+fn scan(input: &str) -> (Vec<Placeholder>, Vec<usize>) {
+    let mut placeholders = Vec::new();
+    let mut escapes = Vec::new();
+    let mut line = 1;
+    let mut line_start = 0;
+    let mut scanned = 0;
+    for cap in PLACEHOLDER_RE.captures_iter(input) {
+        let whole = cap.get(0).unwrap();
+        if cap.name("escape").is_some() {
+            // the second ':' of `bws:://`, or the first '$' of `$${bws:`
+            let dropped = if whole.as_str().starts_with('$') {
+                0
+            } else {
+                4
+            };
+            escapes.push(whole.start() + dropped);
+            continue;
+        }
+        for (i, _) in input[scanned..whole.start()].match_indices('\n') {
+            line += 1;
+            line_start = scanned + i + 1;
+        }
+        scanned = whole.start();
+
+        let group = |plain: &str, quoted: &str| cap.name(plain).or(cap.name(quoted));
+        let (key, by_id) = match (group("key", "qkey"), group("id", "qid")) {
+            (Some(key), _) if cap.name("qkey").is_some() => (unescape_key(key.as_str()), false),
+            (Some(key), _) => (key.as_str().to_string(), false),
+            (None, Some(id)) => (id.as_str().to_string(), true),
+            (None, None) => unreachable!("every placeholder form has a key or id"),
+        };
+        let modifier = match group("modifier", "qmodifier") {
+            Some(name) => {
+                Modifier::parse(name.as_str(), group("value", "qvalue").map(|m| m.as_str()))
+            }
+            None => Ok(Modifier::None),
+        };
+        let filters = filter::parse_chain(group("filters", "qfilters").map_or("", |m| m.as_str()));
+        placeholders.push(Placeholder {
+            start: whole.start(),
+            end: whole.end(),
+            line,
+            column: input[line_start..whole.start()].chars().count() + 1,
+            key,
+            by_id,
+            path: group("path", "qpath").map(|m| m.as_str().to_string()),
+            invalid: modifier.as_ref().err().or(filters.as_ref().err()).cloned(),
+            modifier: modifier.unwrap_or_default(),
+            filters: filters.unwrap_or_default(),
+        });
+    }
+    (placeholders, escapes)
+}
+
+/// Undo `\"` and `\\` in a quoted key
+fn unescape_key(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A template and the placeholders found in it
+#[derive(Clone, Debug)]
+pub struct Template {
+    /// Name used in diagnostics, usually the file path or `<stdin>`
+    pub source: String,
+    pub text: String,
+    pub format: Format,
+    pub placeholders: Vec<Placeholder>,
+    /// Where each placeholder sits in the template syntax
+    slots: Vec<Slot>,
+    /// Characters doubled to escape a literal `bws://` or `${bws:`, dropped from the output
+    escapes: Vec<usize>,
+}
+
+impl Template {
+    /// Parse a raw template, where values are pasted as-is
+    pub fn parse(source: impl Into<String>, text: String) -> Self {
+        Self::parse_as(source, text, Format::Raw)
+    }
+
+    /// Parse a template whose substituted values are quoted and escaped for `format`.
+    /// Placeholders inside comments are left alone.
+    pub fn parse_as(source: impl Into<String>, text: String, format: Format) -> Self {
+        let (found, escapes) = scan(&text);
+        let (placeholders, slots) = found
+            .iter()
+            .cloned()
+            .zip(format::slots(format, &text, &found))
+            .filter(|(_, slot)| *slot != Slot::Comment)
+            .unzip();
+        Self {
+            source: source.into(),
+            text,
+            format,
+            placeholders,
+            slots,
+            escapes,
+        }
+    }
+
+    /// True when there is nothing to substitute or unescape, so the output equals the input
+    pub fn is_static(&self) -> bool {
+        self.placeholders.is_empty() && self.escapes.is_empty()
+    }
+
+    /// Byte offsets where `bws://` or `${bws:` starts outside any placeholder or escape, i.e.
+    /// references too malformed to be recognised at all
+    pub fn stray_references(&self) -> Vec<usize> {
+        let (all, escapes) = scan(&self.text);
+        let mut stray: Vec<usize> = ["bws://", "${bws:"]
+            .iter()
+            .flat_map(|start| self.text.match_indices(start).map(|(i, _)| i))
+            // `bws:://` never matches; `$${bws:` drops the '$' just before the match
+            .filter(|&i| !escapes.contains(&i.wrapping_sub(1)))
+            .filter(|&i| !all.iter().any(|ph| ph.start <= i && i < ph.end))
+            .collect();
+        stray.sort_unstable();
+        stray
+    }
+
+    /// Unique secret keys referenced by the template
+    pub fn keys(&self) -> BTreeSet<String> {
+        self.placeholders
+            .iter()
+            .filter(|p| !p.by_id)
+            .map(|p| p.key.clone())
+            .collect()
+    }
+
+    /// Unique secret ids referenced with the ID form
+    pub fn ids(&self) -> BTreeSet<String> {
+        self.placeholders
+            .iter()
+            .filter(|p| p.by_id)
+            .map(|p| p.key.clone())
+            .collect()
+    }
+
+    /// Append template text, dropping the doubled characters of escapes
+    fn push_literal(&self, out: &mut String, start: usize, end: usize) {
+        let mut pos = start;
+        for &escape in self.escapes.iter().filter(|&&e| e >= start && e < end) {
+            out.push_str(&self.text[pos..escape]);
+            pos = escape + 1;
+        }
+        out.push_str(&self.text[pos..end]);
+    }
+
+    /// Substitute resolved secrets into the template.
+    ///
+    /// Substitution is driven by the placeholder spans in the original text, so replaced values
+    /// are never rescanned. Placeholders that cannot be resolved are left as-is and reported.
+    pub fn render(&self, resolution: &Resolution, verbose: bool) -> Rendered {
+        let mut output = String::with_capacity(self.text.len());
+        let mut placeholders = Vec::with_capacity(self.placeholders.len());
+        let mut last = 0;
+        let mut i = 0;
+
+        while i < self.placeholders.len() {
+            // A bare value may hold several placeholders; it is substituted and quoted as a whole
+            let slot = self.slots[i];
+            let (start, end) = match slot {
+                Slot::Bare { start, end } => (start, end),
+                _ => (self.placeholders[i].start, self.placeholders[i].end),
+            };
+            let group = self.placeholders[i..]
+                .iter()
+                .take_while(|ph| ph.end <= end)
+                .count()
+                .max(1);
+
+            let mut value = String::new();
+            let mut pos = start;
+            let mut errors = Vec::with_capacity(group);
+            let mut defaulted = Vec::with_capacity(group);
+            for ph in &self.placeholders[i..i + group] {
+                self.push_literal(&mut value, pos, ph.start);
+                match self.value(ph, resolution, verbose) {
+                    Ok((v, fallback)) => {
+                        value.push_str(&v);
+                        errors.push(None);
+                        defaulted.push(fallback);
+                    }
+                    Err(message) => {
+                        value.push_str(&self.text[ph.start..ph.end]);
+                        errors.push(Some(message));
+                        defaulted.push(false);
                     }
-                } else {
-                    val_ref.clone()
+                }
+                pos = ph.end;
+            }
+            self.push_literal(&mut value, pos, end);
+
+            self.push_literal(&mut output, last, start);
+            let encoded = match slot {
+                Slot::Verbatim => Ok(self.indent_block(start, end, value)),
+                _ => self.format.encode(slot, &value),
+            };
+            match encoded {
+                Ok(encoded) => output.push_str(&encoded),
+                Err(message) => {
+                    output.push_str(&self.text[start..end]);
+                    errors.iter_mut().for_each(|e| {
+                        e.get_or_insert_with(|| message.clone());
+                    });
+                }
+            }
+
+            let outcomes = errors.into_iter().zip(defaulted);
+            for (ph, (error, defaulted)) in self.placeholders[i..i + group].iter().zip(outcomes) {
+                let secret = match ph.invalid {
+                    None => resolution.lookup(ph).and_then(Result::ok),
+                    Some(_) => None,
                 };
+                placeholders.push(PlaceholderReport {
+                    source: self.source.clone(),
+                    line: ph.line,
+                    column: ph.column,
+                    placeholder: self.text[ph.start..ph.end].to_string(),
+                    key: ph.key.clone(),
+                    path: ph.path.clone(),
+                    resolved: error.is_none(),
+                    defaulted,
+                    required: ph.modifier == Modifier::Required || ph.invalid.is_some(),
+                    error,
+                    by_id: ph.by_id,
+                    secret_id: secret.map(|s| s.id.clone()),
+                    revision_date: secret.map(|s| s.revision_date.clone()),
+                });
+            }
+            last = end;
+            i += group;
+        }
+        self.push_literal(&mut output, last, self.text.len());
+
+        Rendered {
+            output,
+            placeholders,
+            nested: resolution.nested_secrets(),
+        }
+    }
+
+    /// The unescaped text for one placeholder, and whether it came from a `?default` or
+    /// `?optional` fallback because the key or path does not exist
+    fn value(
+        &self,
+        ph: &Placeholder,
+        resolution: &Resolution,
+        verbose: bool,
+    ) -> Result<(String, bool), String> {
+        if let Some(invalid) = &ph.invalid {
+            return Err(invalid.clone());
+        }
+        let missing = match resolution.lookup(ph) {
+            Some(Ok(secret)) => {
+                // the field defaults to the key as written, even when `--env` picked another
+                // secret; the ID form has only the secret's own key
+                let key = match ph.by_id {
+                    true => &secret.key,
+                    false => &ph.key,
+                };
+                match secret_replacement(secret, key, ph.path.as_deref(), verbose) {
+                    Some(value) => return Ok((filter::apply_chain(&ph.filters, value)?, false)),
+                    None => format!(
+                        "path '{}' not found in secret '{}'",
+                        ph.path.as_deref().unwrap_or_default(),
+                        ph.key
+                    ),
+                }
+            }
+            Some(Err(e @ (BwsError::KeyNotFound { .. } | BwsError::NotFound { .. }))) => {
+                e.to_string()
+            }
+            Some(Err(e)) => return Err(e.to_string()),
+            None => return Err(format!("secret '{}' was not resolved", ph.key)),
+        };
+        match &ph.modifier {
+            Modifier::Default(value) => {
+                Ok((filter::apply_chain(&ph.filters, value.clone())?, true))
+            }
+            Modifier::Optional => Ok((String::new(), true)),
+            Modifier::None | Modifier::Required => Err(missing),
+        }
+    }
+
+    /// Indent a multiline value pasted at `start..end` to match the placeholder line
+    fn indent_block(&self, start: usize, end: usize, replacement: String) -> String {
+        if !replacement.contains('\n') {
+            return replacement;
+        }
+
+        // If the placeholder sits alone on an indented line (common with YAML | or |- blocks),
+        // indent each following line of a multiline replacement to match the placeholder
+        // indentation; the first line already sits after the indentation in the template.
+        let line_start = self.text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = self.text[end..]
+            .find('\n')
+            .map(|i| end + i)
+            .unwrap_or(self.text.len());
+        let line = &self.text[line_start..line_end];
+        if line.trim() != &self.text[start..end] {
+            // placeholder is inline with other content; leave replacement unchanged
+            return replacement;
+        }
+        let indent: String = line.chars().take_while(|c| c.is_whitespace()).collect();
+        replacement
+            .lines()
+            .enumerate()
+            .map(|(i, l)| match i {
+                0 => l.to_string(),
+                _ => format!("{}{}", indent, l),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// What happened to one placeholder during rendering. Never contains secret values.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlaceholderReport {
+    pub source: String,
+    pub line: usize,
+    pub column: usize,
+    /// The placeholder text as written in the template
+    pub placeholder: String,
+    pub key: String,
+    pub path: Option<String>,
+    pub resolved: bool,
+    /// The key or path was missing and `?default` or `?optional` supplied the value
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub defaulted: bool,
+    /// Leaving this placeholder unresolved fails the render (`?required`, or malformed)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub required: bool,
+    /// Why the placeholder could not be resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// `key` is a secret id (`bws://id:...`)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub by_id: bool,
+    /// Id of the secret the placeholder was looked up in, when it exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_id: Option<String>,
+    /// `revisionDate` of that secret, recorded by `render --lock`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision_date: Option<String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+impl fmt::Display for PlaceholderReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.source,
+            self.line,
+            self.column,
+            self.placeholder,
+            self.error.as_deref().unwrap_or("resolved")
+        )
+    }
+}
 
-                // If no explicit path was provided, default to extracting value.<key>
-                let target_path = path.map(|p| p.to_string()).unwrap_or_else(|| key.to_string());
+/// Output of rendering a template
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rendered {
+    pub output: String,
+    /// One entry per placeholder, in template order
+    pub placeholders: Vec<PlaceholderReport>,
+    /// Secrets fetched by `--recursive` for references inside other secrets' values
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nested: Vec<NestedSecret>,
+}
+
+/// A secret referenced from inside another secret's value, recorded by `render --lock`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NestedSecret {
+    /// The secret key, or `id:<id>` for the ID form
+    pub reference: String,
+    /// Id of the secret, when it exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision_date: Option<String>,
+}
+
+impl Rendered {
+    /// Placeholders left as-is in `output`
+    pub fn unresolved(&self) -> impl Iterator<Item = &PlaceholderReport> {
+        self.placeholders.iter().filter(|p| !p.resolved)
+    }
+
+    /// Fail with every unresolved reference, for `--strict`
+    pub fn ensure_resolved(&self) -> Result<(), BwsError> {
+        fail_unresolved(self.unresolved())
+    }
 
-                if verbose {
-                    eprintln!(
-                        "extracting path '{}' from parsed value: {}",
-                        target_path,
-                        serde_json::to_string(&parsed_val).unwrap_or_else(|_| "<unprintable>".to_string())
+    /// Fail with the unresolved references that may not be left in the output: `?required`
+    /// and malformed placeholders
+    pub fn ensure_required(&self) -> Result<(), BwsError> {
+        fail_unresolved(self.unresolved().filter(|p| p.required))
+    }
+
+    /// JSON report of every placeholder, for `--report`
+    pub fn report(&self) -> JsonValue {
+        let unresolved = self.unresolved().count();
+        serde_json::json!({
+            "placeholders": self.placeholders,
+            "resolved": self.placeholders.len() - unresolved,
+            "unresolved": unresolved,
+        })
+    }
+}
+
+pub(crate) fn fail_unresolved<'a>(
+    unresolved: impl Iterator<Item = &'a PlaceholderReport>,
+) -> Result<(), BwsError> {
+    let references: Vec<String> = unresolved.map(|p| p.to_string()).collect();
+    if references.is_empty() {
+        Ok(())
+    } else {
+        Err(BwsError::Unresolved { references })
+    }
+}
+
+/// Secrets fetched for a set of keys and ids, ready for substitution
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub(crate) by_key: HashMap<String, Result<SecretResponse, BwsError>>,
+    pub(crate) by_id: HashMap<String, Result<SecretResponse, BwsError>>,
+    /// The org's secret list, once a key lookup needed it
+    list: Option<Vec<SecretIdentifierResponse>>,
+    /// Per-environment keys tried before each key
+    overlay: Option<Overlay>,
+    /// Base key to the environment key that exists for it
+    pub(crate) env_keys: HashMap<String, String>,
+    /// References fetched while expanding values (by id, and the key or id)
+    pub(crate) nested: BTreeSet<(bool, String)>,
+}
+
+impl Resolution {
+    /// The secret for a key, or why it could not be resolved. `None` if the key was not requested.
+    pub fn get(&self, key: &str) -> Option<Result<&SecretResponse, &BwsError>> {
+        self.by_key.get(key).map(|r| r.as_ref())
+    }
+
+    /// The secret for an id referenced with the ID form
+    pub fn get_id(&self, id: &str) -> Option<Result<&SecretResponse, &BwsError>> {
+        self.by_id.get(id).map(|r| r.as_ref())
+    }
+
+    /// The secret a placeholder refers to, by key or by id
+    pub fn for_placeholder(&self, ph: &Placeholder) -> Option<Result<&SecretResponse, &BwsError>> {
+        match ph.by_id {
+            true => self.get_id(&ph.key),
+            false => self.get(&ph.key),
+        }
+    }
+
+    /// The secret whose value a placeholder uses: with `--env`, the environment secret when it
+    /// has the placeholder's path, otherwise the base secret
+    pub fn lookup(&self, ph: &Placeholder) -> Option<Result<&SecretResponse, &BwsError>> {
+        let base = self.for_placeholder(ph);
+        let env = match ph.by_id {
+            true => None,
+            false => self
+                .env_keys
+                .get(&ph.key)
+                .and_then(|env_key| self.get(env_key))
+                .and_then(Result::ok),
+        };
+        match (env, base) {
+            (Some(env), Some(Ok(_))) if !has_path(env, ph.path.as_deref()) => base,
+            (Some(env), _) => Some(Ok(env)),
+            (None, base) => base,
+        }
+    }
+
+    /// The secrets fetched for references inside other secrets' values, as used: with `--env`,
+    /// the environment secret when there is one
+    pub fn nested_secrets(&self) -> Vec<NestedSecret> {
+        self.nested
+            .iter()
+            .map(|(by_id, key)| {
+                let secret = match by_id {
+                    true => self.get_id(key),
+                    false => self
+                        .env_keys
+                        .get(key)
+                        .map_or_else(|| self.get(key), |env_key| self.get(env_key)),
+                };
+                let secret = secret.and_then(Result::ok);
+                NestedSecret {
+                    reference: match by_id {
+                        true => format!("id:{}", key),
+                        false => key.clone(),
+                    },
+                    secret_id: secret.map(|s| s.id.clone()),
+                    revision_date: secret.map(|s| s.revision_date.clone()),
+                }
+            })
+            .collect()
+    }
+
+    /// Every secret that was fetched
+    pub fn secrets(&self) -> impl Iterator<Item = &SecretResponse> {
+        self.by_key
+            .values()
+            .chain(self.by_id.values())
+            .filter_map(|r| r.as_ref().ok())
+    }
+}
+
+/// How references are resolved beyond a plain key or id lookup
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResolveOptions {
+    /// Expand references inside fetched values up to this depth (`--recursive`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recursive: Option<usize>,
+    /// Try per-environment keys first (`--env`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<Overlay>,
+}
+
+/// Resolve keys with one list call and ids directly, without a list call
+pub async fn resolve(
+    client: &BwsClient,
+    org_id: &str,
+    keys: &BTreeSet<String>,
+    ids: &BTreeSet<String>,
+) -> Result<Resolution, BwsError> {
+    resolve_with(client, org_id, keys, ids, &ResolveOptions::default(), false).await
+}
+
+/// [`resolve`] with an environment overlay and recursive expansion
+pub async fn resolve_with(
+    client: &BwsClient,
+    org_id: &str,
+    keys: &BTreeSet<String>,
+    ids: &BTreeSet<String>,
+    options: &ResolveOptions,
+    verbose: bool,
+) -> Result<Resolution, BwsError> {
+    let mut resolution = Resolution {
+        overlay: options.overlay.clone(),
+        ..Default::default()
+    };
+    resolution.fetch(client, org_id, keys, ids).await?;
+    if let Some(max_depth) = options.recursive {
+        resolution
+            .expand(client, org_id, max_depth, verbose)
+            .await?;
+    }
+    Ok(resolution)
+}
+
+/// Resolve keys to secrets with one list call and batched secrets-by-ids calls
+pub async fn resolve_keys(
+    client: &BwsClient,
+    org_id: &str,
+    keys: &BTreeSet<String>,
+) -> Result<Resolution, BwsError> {
+    resolve(client, org_id, keys, &BTreeSet::new()).await
+}
+
+impl Resolution {
+    /// Fetch more keys and ids into this resolution. The secret list is fetched on the first
+    /// call that needs it and reused afterwards.
+    pub(crate) async fn fetch(
+        &mut self,
+        client: &BwsClient,
+        org_id: &str,
+        keys: &BTreeSet<String>,
+        ids: &BTreeSet<String>,
+    ) -> Result<(), BwsError> {
+        self.fetch_keys(client, org_id, keys).await?;
+        self.fetch_ids(client, ids).await
+    }
+
+    async fn fetch_keys(
+        &mut self,
+        client: &BwsClient,
+        org_id: &str,
+        keys: &BTreeSet<String>,
+    ) -> Result<(), BwsError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let overlaid: Vec<(&String, String)> = keys
+            .iter()
+            .filter_map(|key| Some((key, self.overlay.as_ref()?.key_for(key)?)))
+            .collect();
+        let with_overlays: BTreeSet<String>;
+        let keys = match overlaid.is_empty() {
+            true => keys,
+            false => {
+                with_overlays = keys
+                    .iter()
+                    .cloned()
+                    .chain(overlaid.iter().map(|(_, env_key)| env_key.clone()))
+                    .collect();
+                &with_overlays
+            }
+        };
+
+        if self.list.is_none() {
+            self.list = Some(client.list(org_id).await?.data);
+        }
+        let mut ids_by_key: HashMap<String, Vec<String>> = HashMap::new();
+        for item in self.list.iter().flatten().filter(|i| keys.contains(&i.key)) {
+            ids_by_key
+                .entry(item.key.clone())
+                .or_default()
+                .push(item.id.clone());
+        }
+
+        let mut key_by_id: HashMap<String, String> = HashMap::new();
+        for key in keys {
+            match ids_by_key.remove(key) {
+                None => {
+                    self.by_key
+                        .insert(key.clone(), Err(BwsError::KeyNotFound { key: key.clone() }));
+                }
+                Some(ids) if ids.len() > 1 => {
+                    self.by_key.insert(
+                        key.clone(),
+                        Err(BwsError::AmbiguousKey {
+                            key: key.clone(),
+                            ids,
+                        }),
                     );
                 }
+                Some(mut ids) => {
+                    key_by_id.insert(ids.remove(0), key.clone());
+                }
+            }
+        }
 
-                let rep = extract_path(&parsed_val, &target_path)
-                    .map(|v| {
-                        if v.is_string() {
-                            v.as_str().unwrap().to_string()
-                        } else {
-                            serde_json::to_string(&v).unwrap()
-                        }
-                    })
-                    .or_else(|| {
-                        // If no explicit path was provided and extraction failed, fall back to whole value
-                        if path.is_none() {
-                            Some(if parsed_val.is_string() {
-                                parsed_val.as_str().unwrap().to_string()
-                            } else {
-                                serde_json::to_string(&parsed_val).unwrap()
-                            })
-                        } else {
-                            None
-                        }
-                    });
+        let ids: Vec<String> = key_by_id.keys().cloned().collect();
+        for chunk in ids.chunks(BATCH_SIZE) {
+            let secrets = client.get_by_ids(chunk.to_vec()).await?;
+            for secret in secrets.data {
+                if let Some(key) = key_by_id.remove(&secret.id) {
+                    self.by_key.insert(key, Ok(secret));
+                }
+            }
+        }
 
-                if let Some(replacement) = rep {
-                    // If the placeholder sits alone on an indented line (common with YAML | or |- blocks),
-                    // indent each line of a multiline replacement to match the placeholder indentation.
-                    let adjusted = if replacement.contains('\n') {
-                        // find start of the line containing the placeholder in the original input
-                        let line_start = input[..ph_start].rfind('\n').map(|i| i + 1).unwrap_or(0);
-                        let line_end = input[ph_end..].find('\n').map(|i| ph_end + i).unwrap_or(input.len());
-                        let line = &input[line_start..line_end];
-                        // compute indentation (spaces/tabs) at start of the line
-                        let indent: String = line.chars().take_while(|c| c.is_whitespace()).collect();
-                        // check whether the placeholder is the only non-whitespace content on that line
-                        if line.trim() == placeholder {
-                            // indent every line of the replacement with the same indentation
-                            let indented = replacement
-                                .lines()
-                                .map(|l| format!("{}{}", indent, l))
-                                .collect::<Vec<_>>()
-                                .join("\n");
-                            indented
-                        } else {
-                            // placeholder is inline with other content; leave replacement unchanged
-                            replacement
-                        }
-                    } else {
-                        replacement
+        // Anything the sdk-server did not return is reported as missing
+        for key in key_by_id.into_values() {
+            self.by_key
+                .insert(key.clone(), Err(BwsError::KeyNotFound { key }));
+        }
+
+        // An existing environment key is tried before its base key (see `lookup`)
+        for (key, env_key) in overlaid {
+            match self.by_key.get(&env_key) {
+                Some(Ok(_)) => {
+                    self.env_keys.insert(key.clone(), env_key);
+                }
+                Some(Err(BwsError::AmbiguousKey { key: env_key, ids })) => {
+                    let err = BwsError::AmbiguousKey {
+                        key: env_key.clone(),
+                        ids: ids.clone(),
                     };
+                    self.by_key.insert(key.clone(), Err(err));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 
-                    out = out.replacen(placeholder, &adjusted, 1);
+    async fn fetch_ids(
+        &mut self,
+        client: &BwsClient,
+        ids: &BTreeSet<String>,
+    ) -> Result<(), BwsError> {
+        let ids: Vec<String> = ids.iter().cloned().collect();
+        for chunk in ids.chunks(BATCH_SIZE) {
+            match client.get_by_ids(chunk.to_vec()).await {
+                Ok(secrets) => {
+                    for secret in secrets.data {
+                        self.by_id.insert(secret.id.clone(), Ok(secret));
+                    }
                 }
+                // One unknown id fails the whole batch; fetch one by one to find which
+                Err(BwsError::NotFound { .. }) => {
+                    for id in chunk {
+                        self.by_id.insert(id.clone(), client.get(id).await);
+                    }
+                }
+                Err(e) => return Err(e),
             }
         }
+        for id in ids {
+            self.by_id.entry(id.clone()).or_insert_with(|| {
+                Err(BwsError::NotFound {
+                    status: 404,
+                    body: format!("secret with id '{}' not found", id),
+                })
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Render placeholders in a template, replacing bws://key[/path] with secret values
+pub async fn render_template(
+    template: &Template,
+    client: &BwsClient,
+    org_id: &str,
+    options: &ResolveOptions,
+    verbose: bool,
+) -> Result<Rendered, BwsError> {
+    if let Some(socket) = client.agent() {
+        let req = AgentRequest::Render {
+            source: template.source.clone(),
+            text: template.text.clone(),
+            format: template.format,
+            organization_id: org_id.to_string(),
+            options: options.clone(),
+        };
+        return agent::call(socket, &req).await;
     }
+    let (keys, ids) = (template.keys(), template.ids());
+    let resolution = resolve_with(client, org_id, &keys, &ids, options, verbose).await?;
+    Ok(template.render(&resolution, verbose))
+}
+
+/// Text substituted for a placeholder: the value at `path`, or at `key` when no path is
+/// given (falling back to the whole value)
+pub(crate) fn secret_replacement(
+    secret: &SecretResponse,
+    key: &str,
+    path: Option<&str>,
+    verbose: bool,
+) -> Option<String> {
+    // If `value` is a string that contains JSON, try to parse it
+    let parsed_val = secret.parsed_value();
+
+    // If no explicit path was provided, default to extracting value.<key>
+    let target_path = path.unwrap_or(key);
+
+    if verbose {
+        eprintln!(
+            "extracting path '{}' from parsed value: {}",
+            target_path,
+            serde_json::to_string(&parsed_val).unwrap_or_else(|_| "<unprintable>".to_string())
+        );
+    }
+
+    extract_path(&parsed_val, target_path)
+        // If no explicit path was provided and extraction failed, fall back to whole value
+        .or_else(|| path.is_none().then_some(parsed_val))
+        .map(|v| match v {
+            JsonValue::String(s) => s,
+            other => serde_json::to_string(&other).unwrap(),
+        })
+}
 
-    Ok(out)
+/// Decode `%XX` escapes in a modifier value; invalid escapes are kept as-is
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = value
+            .get(i + 1..i + 3)
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
 }
 
 /// Read input from file or stdin
@@ -152,11 +905,18 @@ pub fn read_input(file: Option<PathBuf>) -> anyhow::Result<String> {
     } else {
         let mut s = String::new();
         use std::io::Read;
-        std::io::stdin().read_to_string(&mut s).context("reading stdin")?;
+        std::io::stdin()
+            .read_to_string(&mut s)
+            .context("reading stdin")?;
         Ok(s)
     }
 }
 
+/// Whether `path` exists in a secret's value; a whole-value reference always does
+fn has_path(secret: &SecretResponse, path: Option<&str>) -> bool {
+    path.is_none_or(|path| extract_path(&secret.parsed_value(), path).is_some())
+}
+
 /// Extract a field from a JSON value by path (dot or slash separated)
 pub fn extract_path(val: &JsonValue, path: &str) -> Option<JsonValue> {
     let sep = if path.contains('/') { '/' } else { '.' };
@@ -191,15 +951,236 @@ mod tests {
         assert_eq!(v.unwrap(), serde_json::json!("hello"));
     }
 
+    #[test]
+    fn test_find_placeholders() {
+        let input = "a: bws://harbor/pg_user\nb:  bws://KUBECONFIG\n";
+        let found = find_placeholders(input);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].key, "harbor");
+        assert_eq!(found[0].path.as_deref(), Some("pg_user"));
+        assert_eq!((found[0].line, found[0].column), (1, 4));
+        assert_eq!(&input[found[1].start..found[1].end], "bws://KUBECONFIG");
+        assert_eq!((found[1].line, found[1].column), (2, 5));
+    }
+
+    fn secret(key: &str, value: &str) -> SecretResponse {
+        SecretResponse {
+            id: format!("id-{}", key),
+            organization_id: "org".to_string(),
+            project_id: None,
+            key: key.to_string(),
+            value: value.to_string(),
+            note: String::new(),
+            creation_date: String::new(),
+            revision_date: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_render_uses_spans() {
+        let mut resolution = Resolution::default();
+        resolution.by_key.insert(
+            "harbor".into(),
+            Ok(secret(
+                "harbor",
+                r#"{"harbor": "bws://harbor/pg_user", "pg_user": "u"}"#,
+            )),
+        );
+        resolution.by_key.insert(
+            "gone".into(),
+            Err(BwsError::KeyNotFound { key: "gone".into() }),
+        );
+
+        let template = Template::parse(
+            "t.yaml",
+            "a: bws://harbor\nb: bws://harbor/pg_user\nc: bws://gone\n".to_string(),
+        );
+        let rendered = template.render(&resolution, false);
+        assert_eq!(
+            rendered.output,
+            "a: bws://harbor/pg_user\nb: u\nc: bws://gone\n"
+        );
+        let unresolved: Vec<_> = rendered.unresolved().collect();
+        assert_eq!(unresolved.len(), 1);
+        assert_eq!(
+            unresolved[0].to_string(),
+            "t.yaml:3:4: bws://gone: secret with key 'gone' not found"
+        );
+    }
+
+    #[test]
+    fn test_render_indents_multiline_values() {
+        let mut resolution = Resolution::default();
+        resolution
+            .by_key
+            .insert("tls".into(), Ok(secret("tls", r#"{"cert": "l1\nl2\nl3"}"#)));
+
+        // the template's own indentation stays in front of the first line; only the following
+        // lines get it added
+        let template = Template::parse(
+            "t.yaml",
+            "cert: |\n  bws://tls/cert\ninline: bws://tls/cert\n".to_string(),
+        );
+        assert_eq!(
+            template.render(&resolution, false).output,
+            "cert: |\n  l1\n  l2\n  l3\ninline: l1\nl2\nl3\n"
+        );
+    }
+
+    #[test]
+    fn test_report_and_strict() {
+        let mut resolution = Resolution::default();
+        resolution.by_key.insert(
+            "harbor".into(),
+            Ok(secret("harbor", r#"{"pg_pass": "hunter2"}"#)),
+        );
+
+        let template = Template::parse(
+            "t.yaml",
+            "p: bws://harbor/pg_pass\nq: bws://harbor/missing\n".to_string(),
+        );
+        let rendered = template.render(&resolution, false);
+        let report = rendered.report().to_string();
+        assert!(!report.contains("hunter2"));
+        assert_eq!(rendered.report()["unresolved"], serde_json::json!(1));
+
+        let err = rendered.ensure_resolved().unwrap_err();
+        assert_eq!(err.exit_code(), 10);
+        assert!(err.to_string().contains("t.yaml:2:4"));
+    }
+
+    #[test]
+    fn test_render_yaml_quotes_values() {
+        let mut resolution = Resolution::default();
+        resolution.by_key.insert(
+            "db".into(),
+            Ok(secret("db", r#"{"pass": "a: b #c", "cert": "l1\nl2"}"#)),
+        );
+
+        let template = Template::parse_as(
+            "t.yaml",
+            "# bws://db/pass\np: bws://db/pass\nq: \"bws://db/pass\"\nc: |\n  bws://db/cert\n"
+                .to_string(),
+            Format::Yaml,
+        );
+        assert_eq!(template.placeholders.len(), 3);
+        let rendered = template.render(&resolution, false);
+        assert_eq!(
+            rendered.output,
+            "# bws://db/pass\np: \"a: b #c\"\nq: \"a: b #c\"\nc: |\n  l1\n  l2\n"
+        );
+    }
+
+    #[test]
+    fn test_env_secret_uses_base_key_field() {
+        let mut resolution = Resolution::default();
+        resolution.by_key.insert(
+            "harbor".into(),
+            Ok(secret("harbor", r#"{"harbor": "base"}"#)),
+        );
+        resolution.by_key.insert(
+            "prod_harbor".into(),
+            Ok(secret("prod_harbor", r#"{"harbor": "prod"}"#)),
+        );
+        resolution
+            .env_keys
+            .insert("harbor".into(), "prod_harbor".into());
+        resolution
+            .by_id
+            .insert("id-1".into(), Ok(secret("db", r#"{"db": "by id"}"#)));
+
+        let template = Template::parse("t", "a=bws://harbor b=bws://id:id-1".to_string());
+        assert_eq!(template.render(&resolution, false).output, "a=prod b=by id");
+    }
+
+    #[test]
+    fn test_render_json_escapes() {
+        let mut resolution = Resolution::default();
+        resolution
+            .by_key
+            .insert("db".into(), Ok(secret("db", r#"{"pass": "p"}"#)));
+
+        // the escapes are plain string content in JSON, so the rendered output still parses
+        let input = r#"{"a": "bws://db/pass", "b": "bws:://db/pass", "c": "$${bws:\"db\"}"}"#;
+        let template = Template::parse_as("t.json", input.to_string(), Format::Json);
+        assert_eq!(template.placeholders.len(), 1);
+        assert!(template.stray_references().is_empty());
+        let output = template.render(&resolution, false).output;
+        assert_eq!(
+            output,
+            r#"{"a": "p", "b": "bws://db/pass", "c": "${bws:\"db\"}"}"#
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed["b"], "bws://db/pass");
+    }
+
+    #[test]
+    fn test_render_modifiers() {
+        let mut resolution = Resolution::default();
+        resolution
+            .by_key
+            .insert("db".into(), Ok(secret("db", r#"{"user": "u"}"#)));
+        resolution.by_key.insert(
+            "prod".into(),
+            Err(BwsError::KeyNotFound { key: "prod".into() }),
+        );
+
+        let template = Template::parse(
+            "t",
+            "a=bws://db/user?default=x b=bws://db/pass?default=change%20me c=bws://prod?optional \
+             d=bws://prod?required e=bws://db?bogus"
+                .to_string(),
+        );
+        let rendered = template.render(&resolution, false);
+        assert_eq!(
+            rendered.output,
+            "a=u b=change me c= d=bws://prod?required e=bws://db?bogus"
+        );
+        assert!(rendered.placeholders[1].defaulted);
+        let err = rendered.ensure_required().unwrap_err().to_string();
+        assert!(err.contains("2 unresolved"));
+        assert!(err.contains("unknown modifier 'bogus'"));
+    }
+
+    #[test]
+    fn test_quoted_id_and_escaped_forms() {
+        let input = r##"a=${bws:"my.key \"x\""#db.pass?optional|trim} b=bws://id:0b9e-41/user c=bws:://lit d=$${bws:"k"}"##;
+        let found = find_placeholders(input);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].key, r#"my.key "x""#);
+        assert_eq!(found[0].path.as_deref(), Some("db.pass"));
+        assert_eq!(found[0].modifier, Modifier::Optional);
+        assert_eq!(found[0].filters, vec![Filter::Trim]);
+        assert!(found[1].by_id);
+        assert_eq!(found[1].key, "0b9e-41");
+
+        let mut resolution = Resolution::default();
+        resolution.by_key.insert(
+            r#"my.key "x""#.into(),
+            Ok(secret("k", r#"{"db": {"pass": " p "}}"#)),
+        );
+        resolution
+            .by_id
+            .insert("0b9e-41".into(), Ok(secret("other", r#"{"user": "u"}"#)));
+        let template = Template::parse("t", input.to_string());
+        assert_eq!(template.ids().len(), 1);
+        assert_eq!(template.keys().len(), 1);
+        assert_eq!(
+            template.render(&resolution, false).output,
+            r#"a=p b=u c=bws://lit d=${bws:"k"}"#
+        );
+    }
+
     #[test]
     fn test_bws_regex_captures() {
-        let re = Regex::new(r"bws://([A-Za-z0-9_\-]+)(?:/([A-Za-z0-9_./-]+))?").unwrap();
-        let caps = re.captures("bws://minio_tf_volsync/secret_key").unwrap();
-        assert_eq!(&caps[1], "minio_tf_volsync");
-        assert_eq!(&caps[2], "secret_key");
-
-        let caps2 = re.captures("bws://simplekey").unwrap();
-        assert_eq!(&caps2[1], "simplekey");
-        assert!(caps2.get(2).is_none());
+        let caps = PLACEHOLDER_RE
+            .captures("bws://minio_tf_volsync/secret_key")
+            .unwrap();
+        assert_eq!(&caps["key"], "minio_tf_volsync");
+        assert_eq!(&caps["path"], "secret_key");
+
+        let caps2 = PLACEHOLDER_RE.captures("bws://simplekey").unwrap();
+        assert_eq!(&caps2["key"], "simplekey");
+        assert!(caps2.name("path").is_none());
     }
 }
@@ -0,0 +1,318 @@
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use tokio::process::{Child, Command};
+
+use crate::client::BwsClient;
+use crate::error::BwsError;
+use crate::mask::{Masker, secret_strings};
+use crate::models::SecretResponse;
+use crate::render::{Template, resolve};
+
+/// An environment variable whose value may contain bws:// references
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvVar {
+    pub name: String,
+    pub value: String,
+    /// Where the variable came from, used in diagnostics (e.g. `app.env:3`)
+    pub source: String,
+}
+
+/// Variables from the current process environment whose value holds a bws:// reference
+pub fn env_from_process() -> Vec<EnvVar> {
+    std::env::vars()
+        .filter(|(_, value)| value.contains("bws://") || value.contains("${bws:"))
+        .map(|(name, value)| EnvVar {
+            source: format!("env {}", name),
+            name,
+            value,
+        })
+        .collect()
+}
+
+/// Parse a `NAME=value` assignment as given to `--env`
+pub fn parse_env_assignment(assignment: &str) -> anyhow::Result<EnvVar> {
+    let (name, value) = assignment
+        .split_once('=')
+        .with_context(|| format!("invalid --env '{}', expected NAME=VALUE", assignment))?;
+    anyhow::ensure!(
+        !name.is_empty(),
+        "invalid --env '{}', empty name",
+        assignment
+    );
+    Ok(EnvVar {
+        name: name.to_string(),
+        value: value.to_string(),
+        source: format!("--env {}", name),
+    })
+}
+
+/// Parse a dotenv file: `NAME=value` lines, optional `export ` prefix, `#` comments and
+/// single or double quoted values
+pub fn parse_env_file(path: &Path) -> anyhow::Result<Vec<EnvVar>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading env file {}", path.display()))?;
+    let mut vars = Vec::new();
+    for (idx, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .with_context(|| format!("{}:{}: expected NAME=VALUE", path.display(), idx + 1))?;
+        vars.push(EnvVar {
+            name: name.trim().to_string(),
+            value: unquote(value.trim()),
+            source: format!("{}:{}", path.display(), idx + 1),
+        });
+    }
+    Ok(vars)
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut out = String::with_capacity(value.len());
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) => {
+                    out.push('\n');
+                    chars.next();
+                }
+                ('\\', Some(e @ ('"' | '\\'))) => {
+                    out.push(e);
+                    chars.next();
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    } else if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        value[1..value.len() - 1].to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Environment variables with their references resolved
+#[derive(Clone, Debug)]
+pub struct ResolvedEnv {
+    pub vars: Vec<(String, String)>,
+    /// The secrets that were substituted
+    pub secrets: Vec<SecretResponse>,
+    /// Values of the variables that had references substituted
+    pub substituted: Vec<String>,
+}
+
+impl ResolvedEnv {
+    /// Masker concealing both the secrets and the variable values built from them
+    pub fn masker(&self) -> Masker {
+        Masker::new(
+            self.substituted
+                .iter()
+                .cloned()
+                .chain(self.secrets.iter().flat_map(secret_strings)),
+        )
+    }
+}
+
+/// Resolve the bws:// references in every variable with a single planning pass.
+///
+/// Later variables with the same name override earlier ones. Fails with every unresolved
+/// reference rather than starting a process with a half-configured environment.
+pub async fn resolve_env(
+    client: &BwsClient,
+    org_id: &str,
+    vars: Vec<EnvVar>,
+    verbose: bool,
+) -> Result<ResolvedEnv, BwsError> {
+    let templates: Vec<(String, Template)> = vars
+        .into_iter()
+        .map(|v| (v.name, Template::parse(v.source, v.value)))
+        .collect();
+    let keys: BTreeSet<String> = templates.iter().flat_map(|(_, t)| t.keys()).collect();
+    let ids: BTreeSet<String> = templates.iter().flat_map(|(_, t)| t.ids()).collect();
+    let resolution = resolve(client, org_id, &keys, &ids).await?;
+
+    let mut resolved = Vec::with_capacity(templates.len());
+    let mut substituted = Vec::new();
+    let mut references = Vec::new();
+    for (name, template) in templates {
+        let rendered = template.render(&resolution, verbose);
+        references.extend(rendered.unresolved().map(|p| p.to_string()));
+        if !rendered.placeholders.is_empty() {
+            substituted.push(rendered.output.clone());
+        }
+        resolved.push((name, rendered.output));
+    }
+    if !references.is_empty() {
+        return Err(BwsError::Unresolved { references });
+    }
+    Ok(ResolvedEnv {
+        vars: resolved,
+        secrets: resolution.secrets().cloned().collect(),
+        substituted,
+    })
+}
+
+/// Run a command with extra environment variables, forwarding signals to it, and wait for it.
+///
+/// With a masker, the child's stdout and stderr are piped through it before being written to
+/// ours.
+pub async fn run_command(
+    command: &[String],
+    env: &[(String, String)],
+    masker: Option<&Masker>,
+) -> anyhow::Result<ExitStatus> {
+    let (program, args) = command.split_first().context("no command given")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args).envs(env.iter().map(|(k, v)| (k, v)));
+    if masker.is_some() {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("failed to start {}", program))?;
+
+    let Some(masker) = masker else {
+        return wait_forwarding_signals(&mut child).await;
+    };
+    let stdout = child.stdout.take().context("child stdout not piped")?;
+    let stderr = child.stderr.take().context("child stderr not piped")?;
+    let (status, out, err) = tokio::join!(
+        wait_forwarding_signals(&mut child),
+        masker.copy(stdout, tokio::io::stdout()),
+        masker.copy(stderr, tokio::io::stderr()),
+    );
+    out.context("copying child stdout")?;
+    err.context("copying child stderr")?;
+    status
+}
+
+/// Wait for the child, relaying termination and user signals received by this process.
+/// SIGINT and SIGQUIT are not relayed while the child is in the terminal's foreground process
+/// group, since the terminal sends Ctrl-C and Ctrl-\ to the child as well.
+#[cfg(unix)]
+async fn wait_forwarding_signals(child: &mut Child) -> anyhow::Result<ExitStatus> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let pid = child
+        .id()
+        .context("child exited before it could be waited on")? as libc::pid_t;
+    let mut forwarders = Vec::new();
+    for sig in [
+        libc::SIGINT,
+        libc::SIGTERM,
+        libc::SIGHUP,
+        libc::SIGQUIT,
+        libc::SIGUSR1,
+        libc::SIGUSR2,
+    ] {
+        let from_terminal = matches!(sig, libc::SIGINT | libc::SIGQUIT);
+        let mut stream = signal(SignalKind::from_raw(sig)).context("installing signal handler")?;
+        forwarders.push(tokio::spawn(async move {
+            while stream.recv().await.is_some() {
+                if !(from_terminal && in_terminal_foreground(pid)) {
+                    // SAFETY: kill has no memory safety preconditions; pid is our own child.
+                    unsafe {
+                        libc::kill(pid, sig);
+                    }
+                }
+            }
+        }));
+    }
+
+    let status = child.wait().await.context("waiting for child process");
+    for forwarder in forwarders {
+        forwarder.abort();
+    }
+    status
+}
+
+#[cfg(not(unix))]
+async fn wait_forwarding_signals(child: &mut Child) -> anyhow::Result<ExitStatus> {
+    child.wait().await.context("waiting for child process")
+}
+
+/// Exit code to propagate for a finished child: its own code, or 128 + signal if it was killed
+pub fn exit_code(status: ExitStatus) -> u8 {
+    if let Some(code) = status.code() {
+        return code as u8;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(sig) = status.signal() {
+            return 128u8.wrapping_add(sig as u8);
+        }
+    }
+    1
+}
+
+/// Whether the process group of `pid` is the foreground group of our controlling terminal
+#[cfg(unix)]
+fn in_terminal_foreground(pid: libc::pid_t) -> bool {
+    use std::os::fd::AsRawFd;
+
+    let Ok(tty) = std::fs::File::open("/dev/tty") else {
+        return false;
+    };
+    // SAFETY: tcgetpgrp and getpgid only read process state; the fd stays open for the call.
+    let (foreground, group) = unsafe { (libc::tcgetpgrp(tty.as_raw_fd()), libc::getpgid(pid)) };
+    foreground > 0 && foreground == group
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env_file() {
+        let dir = std::env::temp_dir().join(format!("bws-run-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.env");
+        std::fs::write(
+            &path,
+            "# comment\nexport DB_USER=bws://harbor/pg_user\n\nDB_PASS=\"bws://harbor/pg_pass\"\nPLAIN='a b'\n",
+        )
+        .unwrap();
+
+        let vars = parse_env_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vars.len(), 3);
+        assert_eq!(vars[0].name, "DB_USER");
+        assert_eq!(vars[1].value, "bws://harbor/pg_pass");
+        assert_eq!(vars[1].source, format!("{}:4", path.display()));
+        assert_eq!(vars[2].value, "a b");
+    }
+
+    #[test]
+    fn test_parse_env_assignment() {
+        let var = parse_env_assignment("DSN=postgres://u:bws://harbor/pg_pass@db").unwrap();
+        assert_eq!(var.name, "DSN");
+        assert_eq!(var.value, "postgres://u:bws://harbor/pg_pass@db");
+        assert!(parse_env_assignment("NOVALUE").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_forwards_sigint_sent_to_parent_alone() {
+        // setsid moves the child out of any terminal's foreground group, as under a supervisor
+        let script = "trap 'exit 7' INT; i=0; while [ $i -lt 50 ]; do sleep 0.1; i=$((i+1)); done";
+        let command: Vec<String> = ["setsid", "sh", "-c", script]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let run = tokio::spawn(async move { run_command(&command, &[], None).await });
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        // SAFETY: kill has no memory safety preconditions; the handler is installed by now.
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGINT);
+        }
+        let status = run.await.unwrap().unwrap();
+        assert_eq!(status.code(), Some(7));
+    }
+}
@@ -0,0 +1,400 @@
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// TLS options shared by the http client and `doctor`, so both connect the same way
+#[derive(Clone, Debug, Default)]
+pub struct TlsSettings {
+    /// Disable certificate validation (pins are still checked)
+    pub insecure: bool,
+    /// PEM bundles trusted on top of the bundled roots
+    pub ca_certs: Vec<PathBuf>,
+    /// Client certificate for mTLS: a PEM chain, or a PKCS#12 bundle (`.p12`/`.pfx`) with its key
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key of a PEM `client_cert`; defaults to a key inside `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Password of a PKCS#12 `client_cert`
+    pub client_cert_password: Option<String>,
+    /// `sha256//<base64>` digests of a SubjectPublicKeyInfo: the server's key, or the key of a CA
+    /// its certificate verifies up to. With `insecure`, only the server's own key counts.
+    pub pins: Vec<String>,
+}
+
+/// The roots reqwest trusts (the bundled Mozilla set), plus every certificate in `ca_certs`
+pub fn root_store(ca_certs: &[PathBuf]) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    for path in ca_certs {
+        for der in read_pem_certs(path)? {
+            roots
+                .add(&Certificate(der))
+                .with_context(|| format!("adding CA certificate from {}", path.display()))?;
+        }
+    }
+    Ok(roots)
+}
+
+/// Every certificate in a PEM file; a file without any is an error
+pub fn read_pem_certs(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .with_context(|| format!("parsing {}", path.display()))?;
+    anyhow::ensure!(
+        !certs.is_empty(),
+        "no PEM certificates found in {}",
+        path.display()
+    );
+    Ok(certs)
+}
+
+/// The rustls config every connection uses: the bundled roots plus `ca_certs` (or no verification
+/// with `insecure`), the client certificate if any, and the SPKI pins
+pub fn client_config(settings: &TlsSettings) -> anyhow::Result<ClientConfig> {
+    let roots = root_store(&settings.ca_certs)?;
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone());
+    let mut config = match &settings.client_cert {
+        Some(path) => {
+            let (certs, key) = client_identity(
+                path,
+                settings.client_key.as_deref(),
+                settings.client_cert_password.as_deref(),
+            )?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .with_context(|| format!("using client certificate {}", path.display()))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    config
+        .dangerous()
+        .set_certificate_verifier(verifier(settings, roots)?);
+    Ok(config)
+}
+
+/// The server certificate check: `roots`, or nothing with `insecure`, then the pins if any
+fn verifier(
+    settings: &TlsSettings,
+    roots: RootCertStore,
+) -> anyhow::Result<Arc<dyn ServerCertVerifier>> {
+    let mut verifier: Arc<dyn ServerCertVerifier> = match settings.insecure {
+        true => Arc::new(AcceptAnyServerCert),
+        false => Arc::new(WebPkiVerifier::new(roots, None)),
+    };
+    if !settings.pins.is_empty() {
+        let pins: Vec<[u8; 32]> = settings
+            .pins
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<anyhow::Result<_>>()?;
+        verifier = Arc::new(PinnedVerifier {
+            inner: verifier,
+            anchors: pinned_anchors(&settings.ca_certs, &pins)?,
+            pins,
+            insecure: settings.insecure,
+        });
+    }
+    Ok(verifier)
+}
+
+/// The certificate chain and key to present to the server
+fn client_identity(
+    cert: &Path,
+    key: Option<&Path>,
+    password: Option<&str>,
+) -> anyhow::Result<(Vec<Certificate>, PrivateKey)> {
+    let is_pkcs12 = cert
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("p12") || e.eq_ignore_ascii_case("pfx"));
+    if is_pkcs12 {
+        anyhow::ensure!(
+            key.is_none(),
+            "a PKCS#12 client certificate contains its key; drop --client-key"
+        );
+        let data = std::fs::read(cert).with_context(|| format!("reading {}", cert.display()))?;
+        let store = p12_keystore::KeyStore::from_pkcs12(&data, password.unwrap_or_default())
+            .map_err(|e| anyhow::anyhow!("{:?}", e))
+            .with_context(|| {
+                format!(
+                    "opening PKCS#12 bundle {} (wrong password?)",
+                    cert.display()
+                )
+            })?;
+        let (_, chain) = store
+            .private_key_chain()
+            .with_context(|| format!("no private key in {}", cert.display()))?;
+        let certs = chain
+            .chain()
+            .iter()
+            .map(|c| Certificate(c.as_der().to_vec()))
+            .collect();
+        return Ok((certs, PrivateKey(chain.key().to_vec())));
+    }
+
+    let certs = read_pem_certs(cert)?.into_iter().map(Certificate).collect();
+    let key_path = key.unwrap_or(cert);
+    let pem = std::fs::read(key_path).with_context(|| format!("reading {}", key_path.display()))?;
+    let items = rustls_pemfile::read_all(&mut pem.as_slice())
+        .with_context(|| format!("parsing {}", key_path.display()))?;
+    let key = items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| {
+            format!(
+                "no private key in {}; pass it with --client-key",
+                key_path.display()
+            )
+        })?;
+    Ok((certs, key))
+}
+
+/// The trusted certificates whose key is pinned, as trust anchors: bundled roots and `ca_certs`
+fn pinned_anchors(
+    ca_certs: &[PathBuf],
+    pins: &[[u8; 32]],
+) -> anyhow::Result<Vec<OwnedTrustAnchor>> {
+    let mut anchors: Vec<OwnedTrustAnchor> = webpki_roots::TLS_SERVER_ROOTS
+        .iter()
+        .filter(|ta| {
+            // the bundled roots hold the SubjectPublicKeyInfo without its SEQUENCE header
+            let digest: [u8; 32] = Sha256::digest(der_sequence(ta.spki)).into();
+            pins.contains(&digest)
+        })
+        .map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })
+        .collect();
+    for path in ca_certs {
+        for der in read_pem_certs(path)? {
+            if spki_sha256(&der).is_some_and(|digest| pins.contains(&digest)) {
+                let mut store = RootCertStore::empty();
+                store
+                    .add(&Certificate(der))
+                    .with_context(|| format!("adding CA certificate from {}", path.display()))?;
+                anchors.extend(store.roots);
+            }
+        }
+    }
+    Ok(anchors)
+}
+
+/// DER SEQUENCE around `contents`
+fn der_sequence(contents: &[u8]) -> Vec<u8> {
+    let len = contents.len();
+    let mut der = vec![0x30];
+    match len {
+        0..=0x7f => der.push(len as u8),
+        0x80..=0xff => der.extend([0x81, len as u8]),
+        _ => der.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    der.extend_from_slice(contents);
+    der
+}
+
+/// A pin as `sha256//<base64>` (the curl form) or bare base64
+fn parse_pin(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let encoded = pin.strip_prefix("sha256//").unwrap_or(pin);
+    BASE64
+        .decode(encoded)
+        .ok()
+        .and_then(|digest| digest.try_into().ok())
+        .with_context(|| format!("'{}' is not a base64 SHA-256 pin", pin))
+}
+
+/// SHA-256 of a certificate's SubjectPublicKeyInfo, as pinned with `--pin-sha256`
+pub fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(Sha256::digest(parsed.tbs_certificate.subject_pki.raw).into())
+}
+
+/// Accepts every server certificate, for `--insecure` and for showing an untrusted chain
+pub struct AcceptAnyServerCert;
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Runs the usual verification, then requires a pinned key: the server's own, or one its
+/// certificate verifies up to.
+///
+/// A pinned certificate merely sent along in the chain does not count, since anyone can append a
+/// public CA certificate to theirs.
+struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<[u8; 32]>,
+    /// Trusted certificates with a pinned key
+    anchors: Vec<OwnedTrustAnchor>,
+    /// `inner` verified nothing, so only the end entity's key counts
+    insecure: bool,
+}
+
+impl PinnedVerifier {
+    fn is_pinned(&self, cert: &Certificate) -> bool {
+        spki_sha256(&cert.0).is_some_and(|digest| self.pins.contains(&digest))
+    }
+
+    /// Whether `end_entity` verifies with a pinned key as its only trust anchor
+    fn issued_by_pinned_key(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> bool {
+        let mut anchors = self.anchors.clone();
+        for cert in intermediates.iter().filter(|cert| self.is_pinned(cert)) {
+            let mut store = RootCertStore::empty();
+            if store.add(cert).is_ok() {
+                anchors.extend(store.roots);
+            }
+        }
+        anchors.into_iter().any(|anchor| {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(std::iter::once(anchor));
+            WebPkiVerifier::new(roots, None)
+                .verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    &mut std::iter::empty(),
+                    ocsp_response,
+                    now,
+                )
+                .is_ok()
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let pinned = self.is_pinned(end_entity)
+            || (!self.insecure
+                && self.issued_by_pinned_key(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                ));
+        match pinned {
+            true => Ok(verified),
+            false => Err(rustls::Error::General(
+                "neither the server's key nor a CA key it verifies up to matches a --pin-sha256 pin"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pin() {
+        let digest = [7u8; 32];
+        let encoded = BASE64.encode(digest);
+        assert_eq!(parse_pin(&format!("sha256//{}", encoded)).unwrap(), digest);
+        assert_eq!(parse_pin(&encoded).unwrap(), digest);
+        assert!(parse_pin("sha256//AAEC").is_err());
+        assert!(parse_pin("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_pins_follow_the_verified_chain() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/pinning");
+        let cert = |name: &str| Certificate(read_pem_certs(&dir.join(name)).unwrap().remove(0));
+        let (pinned_ca, pinned_leaf) = (cert("pinned-ca.pem"), cert("pinned-leaf.pem"));
+        let other_leaf = cert("other-leaf.pem");
+        let pin = |cert: &Certificate| BASE64.encode(spki_sha256(&cert.0).unwrap());
+        let name = ServerName::try_from("localhost").unwrap();
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_900_000_000);
+
+        // both CAs are trusted, as if the other one had mis-issued a certificate for the server
+        let verify = |insecure: bool, pins: Vec<String>, chain: &[&Certificate]| {
+            let settings = TlsSettings {
+                insecure,
+                ca_certs: vec![dir.join("pinned-ca.pem"), dir.join("other-ca.pem")],
+                pins,
+                ..Default::default()
+            };
+            let verifier = verifier(&settings, root_store(&settings.ca_certs).unwrap()).unwrap();
+            let intermediates: Vec<Certificate> = chain[1..].iter().map(|c| (*c).clone()).collect();
+            verifier
+                .verify_server_cert(
+                    chain[0],
+                    &intermediates,
+                    &name,
+                    &mut std::iter::empty(),
+                    &[],
+                    now,
+                )
+                .is_ok()
+        };
+        let ca_pin = vec![pin(&pinned_ca)];
+        assert!(verify(false, ca_pin.clone(), &[&pinned_leaf]));
+        assert!(verify(false, ca_pin.clone(), &[&pinned_leaf, &pinned_ca]));
+        assert!(!verify(false, ca_pin.clone(), &[&other_leaf]));
+        // appending the pinned CA's certificate does not make another leaf pinned
+        assert!(!verify(false, ca_pin.clone(), &[&other_leaf, &pinned_ca]));
+        assert!(!verify(true, ca_pin, &[&other_leaf, &pinned_ca]));
+        // with --insecure only the server's own key counts
+        assert!(verify(true, vec![pin(&pinned_leaf)], &[&pinned_leaf]));
+        assert!(!verify(
+            true,
+            vec![pin(&pinned_ca)],
+            &[&pinned_leaf, &pinned_ca]
+        ));
+    }
+}
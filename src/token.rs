@@ -0,0 +1,319 @@
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::header::HeaderValue;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::error::BwsError;
+
+/// Keyring service the `--access-token-keyring` entries are stored under
+pub const KEYRING_SERVICE: &str = "bws-connector";
+
+/// Where the Warden access token is read from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenSource {
+    /// Given directly: `--access-token`, `WARDEN_ACCESS_TOKEN` or a profile's `access_token_env`
+    Value(String),
+    /// A file holding the token, such as a mounted Kubernetes secret
+    File(PathBuf),
+    /// A shell command printing the token on stdout
+    Command(String),
+    /// An entry of the OS keyring (Secret Service), under [`KEYRING_SERVICE`]
+    Keyring(String),
+    /// The first line of stdin
+    Stdin,
+}
+
+impl TokenSource {
+    /// Read the token; surrounding whitespace is dropped and an empty token is an error
+    pub fn read(&self) -> anyhow::Result<String> {
+        let token = match self {
+            Self::Value(token) => token.clone(),
+            Self::File(path) => read_file(path)?,
+            Self::Command(command) => run_command(command)?,
+            Self::Keyring(user) => read_keyring(user)?,
+            Self::Stdin => read_stdin_line().context("reading access token from stdin")?,
+        };
+        let token = token.trim();
+        anyhow::ensure!(!token.is_empty(), "access token from {} is empty", self);
+        Ok(token.to_string())
+    }
+}
+
+impl std::fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Value(_) => write!(f, "the given value"),
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Command(command) => write!(f, "command `{}`", command),
+            Self::Keyring(user) => write!(f, "keyring entry {}/{}", KEYRING_SERVICE, user),
+            Self::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("reading access token file {}", path.display()))
+}
+
+/// Read the first line of stdin straight from fd 0. `std::io::stdin()` would buffer past the
+/// newline, taking input meant for the template, `mask` or the `run` child.
+#[cfg(unix)]
+fn read_stdin_line() -> std::io::Result<String> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fd 0 is open for the life of the process, and ManuallyDrop keeps it from being
+    // closed when the File goes out of scope.
+    let mut stdin = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(0) });
+    read_line_unbuffered(&mut *stdin)
+}
+
+#[cfg(not(unix))]
+fn read_stdin_line() -> std::io::Result<String> {
+    read_line_unbuffered(&mut std::io::stdin())
+}
+
+/// Read one line a byte at a time, consuming its newline but nothing after it
+fn read_line_unbuffered(reader: &mut impl std::io::Read) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    String::from_utf8(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn run_command(command: &str) -> anyhow::Result<String> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .with_context(|| format!("running access token command `{}`", command))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "access token command `{}` failed ({}): {}",
+        command,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    String::from_utf8(output.stdout).context("access token command printed invalid UTF-8")
+}
+
+#[cfg(feature = "keyring")]
+fn read_keyring(user: &str) -> anyhow::Result<String> {
+    let read = || -> anyhow::Result<String> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, user)?;
+        entry
+            .get_password()
+            .with_context(|| format!("reading keyring entry {}/{}", KEYRING_SERVICE, user))
+    };
+    // the Secret Service client blocks on its own D-Bus calls
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => tokio::task::block_in_place(read),
+        Err(_) => read(),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn read_keyring(_user: &str) -> anyhow::Result<String> {
+    anyhow::bail!("keyring support is not compiled in; rebuild with `--features keyring`")
+}
+
+/// A machine-account access token, `<version>.<access token id>.<client secret>:<encryption key>`,
+/// parsed without contacting the server.
+///
+/// Only the version and id are kept; the secret parts are checked for shape and then dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessToken {
+    pub version: String,
+    pub access_token_id: String,
+    /// `sha256:` and the first 16 hex digits of the token's SHA-256, to tell tokens apart
+    pub fingerprint: String,
+}
+
+impl AccessToken {
+    pub fn parse(token: &str) -> Result<Self, BwsError> {
+        let invalid = |reason: &str| BwsError::InvalidToken {
+            reason: reason.to_string(),
+        };
+        let (credentials, key) = token
+            .split_once(':')
+            .ok_or_else(|| invalid("expected <version>.<id>.<secret>:<key>, found no ':'"))?;
+        let mut parts = credentials.splitn(3, '.');
+        let (Some(version), Some(id), Some(secret)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid(
+                "expected <version>.<id>.<secret> before the ':', found fewer than three parts",
+            ));
+        };
+        if version != "0" {
+            return Err(invalid(&format!("unsupported version '{}'", version)));
+        }
+        if !is_uuid(id) {
+            return Err(invalid("the access token id is not a UUID"));
+        }
+        if secret.is_empty() || !secret.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid("the client secret is empty or not alphanumeric"));
+        }
+        match BASE64.decode(key) {
+            Ok(key) if key.len() == 16 => {}
+            Ok(key) => {
+                return Err(invalid(&format!(
+                    "the encryption key is {} bytes, expected 16",
+                    key.len()
+                )));
+            }
+            Err(_) => return Err(invalid("the encryption key is not valid base64")),
+        }
+        let digest = Sha256::digest(token.as_bytes());
+        let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(Self {
+            version: version.to_string(),
+            access_token_id: id.to_string(),
+            fingerprint: format!("sha256:{}", hex),
+        })
+    }
+
+    /// The token with its secret parts replaced, safe to print
+    pub fn redacted(&self) -> String {
+        format!(
+            "{}.{}.<redacted>:<redacted>",
+            self.version, self.access_token_id
+        )
+    }
+}
+
+/// `8-4-4-4-12` hex digits
+fn is_uuid(s: &str) -> bool {
+    let groups: Vec<&str> = s.split('-').collect();
+    groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(g, len)| g.len() == len && g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// A token file that is read again when it changes, for long-lived clients such as the agent.
+///
+/// Mounted Kubernetes secrets are replaced by swapping a symlink, so the file's modification
+/// time and size are compared on every use rather than watched.
+pub struct TokenFile {
+    path: PathBuf,
+    state: Mutex<(Option<(SystemTime, u64)>, HeaderValue)>,
+}
+
+impl TokenFile {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let stamp = stamp(path);
+        let token = header_value(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new((stamp, token)),
+        })
+    }
+
+    /// The current token, re-read when the file changed. If the new contents cannot be read,
+    /// the previous token is kept.
+    pub fn current(&self) -> HeaderValue {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = stamp(&self.path);
+        if now.is_some() && now != state.0 {
+            match header_value(&self.path) {
+                Ok(token) => *state = (now, token),
+                Err(e) => eprintln!("warning: keeping the previous access token: {:#}", e),
+            }
+        }
+        state.1.clone()
+    }
+}
+
+fn header_value(path: &Path) -> anyhow::Result<HeaderValue> {
+    let token = TokenSource::File(path.to_path_buf()).read()?;
+    let mut value = HeaderValue::from_str(&token)
+        .with_context(|| format!("access token in {} is not a valid header", path.display()))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_and_command_sources() {
+        let path = std::env::temp_dir().join(format!("bws-token-test-{}", std::process::id()));
+        std::fs::write(&path, "0.first\n").unwrap();
+        assert_eq!(TokenSource::File(path.clone()).read().unwrap(), "0.first");
+
+        let file = TokenFile::open(&path).unwrap();
+        assert_eq!(file.current(), "0.first");
+        std::fs::write(&path, "0.second-token\n").unwrap();
+        assert_eq!(file.current(), "0.second-token");
+        // an empty or unreadable file keeps the previous token
+        std::fs::write(&path, "\n").unwrap();
+        assert_eq!(file.current(), "0.second-token");
+        std::fs::remove_file(&path).unwrap();
+
+        let command = TokenSource::Command("printf '  0.from-command\\n'".into());
+        assert_eq!(command.read().unwrap(), "0.from-command");
+        let failing = TokenSource::Command("echo nope >&2; exit 3".into());
+        let err = failing.read().unwrap_err().to_string();
+        assert!(err.ends_with(": nope"), "{}", err);
+    }
+
+    #[test]
+    fn test_stdin_line_leaves_the_rest() {
+        let mut input: &[u8] = b"0.token\nrest of the input\n";
+        assert_eq!(read_line_unbuffered(&mut input).unwrap(), "0.token");
+        assert_eq!(input, b"rest of the input\n");
+    }
+
+    #[test]
+    fn test_parse_access_token() {
+        let token = "0.6a3b1c9e-4f2d-4e8a-9b7c-1d2e3f4a5b6c.Zx9yQ2wErT5uIoP1aSdF3gHjK7lM4n:AAECAwQFBgcICQoLDA0ODw==";
+        let parsed = AccessToken::parse(token).unwrap();
+        assert_eq!(
+            parsed.access_token_id,
+            "6a3b1c9e-4f2d-4e8a-9b7c-1d2e3f4a5b6c"
+        );
+        assert_eq!(
+            parsed.redacted(),
+            "0.6a3b1c9e-4f2d-4e8a-9b7c-1d2e3f4a5b6c.<redacted>:<redacted>"
+        );
+        assert!(parsed.fingerprint.starts_with("sha256:") && parsed.fingerprint.len() == 23);
+
+        let reason = |token: &str| match AccessToken::parse(token) {
+            Err(BwsError::InvalidToken { reason }) => reason,
+            other => panic!("expected an invalid token, got {:?}", other),
+        };
+        assert!(reason("t").contains("no ':'"));
+        assert_eq!(
+            reason(&token.replacen("0.", "1.", 1)),
+            "unsupported version '1'"
+        );
+        assert_eq!(
+            reason(&token.replace("-4f2d", "")),
+            "the access token id is not a UUID"
+        );
+        assert_eq!(
+            reason(&token.replace("AAECAwQFBgcICQoLDA0ODw==", "AAEC")),
+            "the encryption key is 3 bytes, expected 16"
+        );
+    }
+}